@@ -36,18 +36,87 @@ fn bench_adversarial_backtracking(c: &mut Criterion) {
         ]),
     );
 
-    let nfa = ast.into_nfa();
+    let derivatives_dfa = ast.to_dfa_by_derivatives().unwrap();
+    let ast2 = ast.clone();
+    let nfa = ast.into_nfa().unwrap();
     let nfa2 = nfa.clone();
-    let dfa = nfa.to_dfa();
+    let epsilon_free = nfa.remove_epsilons();
+    let program = nfa.to_program();
+    let program2 = program.clone();
+    let program3 = program.clone();
+    let program4 = program.clone();
+    let mut cache = Cache::new(&program3);
+    let bits = BitParallel::new(&program).unwrap();
+    let lazy_dfa = LazyDFA::new(program.clone()).unwrap();
+    let mut lazy_cache = LazyCache::new(&lazy_dfa);
+    let dfa = nfa.to_dfa().unwrap();
 
     let functions = vec!(
-        Fun::new("Backtracking NFA", move |b, i| b.iter(|| nfa.run_backtracking(*i))),
-        Fun::new("NFA", move |b, i| b.iter(|| nfa2.run(*i))),
-        Fun::new("DFA", move |b, i| b.iter(|| dfa.run(*i))),
+        Fun::new("Backtracking NFA", move |b, i: &&str| b.iter(|| nfa.run_backtracking(i))),
+        Fun::new("NFA", move |b, i: &&str| b.iter(|| nfa2.run(i))),
+        Fun::new("Epsilon-free NFA", move |b, i: &&str| b.iter(|| epsilon_free.run(i))),
+        Fun::new("Backtracking Program", move |b, i: &&str| b.iter(|| program.run_backtracking(i))),
+        Fun::new("Bounded backtracking Program", move |b, i: &&str| {
+            b.iter(|| program4.run_bounded_backtracking(i))
+        }),
+        Fun::new("Program", move |b, i: &&str| b.iter(|| program2.run(i))),
+        Fun::new("Program with cache", move |b, i: &&str| {
+            b.iter(|| program3.run_with(&mut cache, i))
+        }),
+        Fun::new("Bit-parallel", move |b, i: &&str| b.iter(|| bits.run(i))),
+        Fun::new("Lazy DFA", move |b, i: &&str| {
+            b.iter(|| lazy_dfa.run_with(&mut lazy_cache, i))
+        }),
+        Fun::new("DFA", move |b, i: &&str| b.iter(|| dfa.run(i))),
+        Fun::new("Derivatives", move |b, i: &&str| b.iter(|| ast2.run(i))),
+        Fun::new("Derivatives DFA", move |b, i: &&str| b.iter(|| derivatives_dfa.run(i))),
     );
 
     c.bench_functions("Adversarial", functions, "xxxxxxxxx");
 }
 
-criterion_group!(benches, bench_adversarial_backtracking);
+fn zero_one(n: usize) -> AST {
+    // (0|1)*1(0|1)^n
+    let zero_or_one = || AST::or(vec![AST::literal('0'), AST::literal('1')]);
+
+    let mut children = vec![AST::star(zero_or_one()), AST::literal('1')];
+    for _ in 0..n {
+        children.push(zero_or_one());
+    }
+
+    AST::concat(children)
+}
+
+fn bench_constructions(c: &mut Criterion) {
+    let thompson = Compiler::new().compile(zero_one(8)).unwrap();
+    let glushkov = Compiler::new()
+        .construction(Construction::Glushkov)
+        .compile(zero_one(8)).unwrap();
+    let thompson2 = thompson.clone();
+    let glushkov2 = glushkov.clone();
+
+    let functions = vec!(
+        Fun::new("Thompson to_dfa", move |b, _: &&str| b.iter(|| thompson.to_dfa())),
+        Fun::new("Glushkov to_dfa", move |b, _: &&str| b.iter(|| glushkov.to_dfa())),
+        Fun::new("Thompson NFA", move |b, i: &&str| b.iter(|| thompson2.run(i))),
+        Fun::new("Glushkov NFA", move |b, i: &&str| b.iter(|| glushkov2.run(i))),
+    );
+
+    c.bench_functions("Constructions", functions, "0110100101101001011010010110100101101");
+}
+
+fn bench_long_pattern(c: &mut Criterion) {
+    let ast = AST::concat((0..10_000).map(|_| AST::literal('a')).collect());
+
+    c.bench_function("Thompson construction, 10k literals", move |b| {
+        b.iter(|| ast.clone().into_nfa().unwrap())
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_adversarial_backtracking,
+    bench_constructions,
+    bench_long_pattern
+);
 criterion_main!(benches);
\ No newline at end of file
@@ -77,7 +77,7 @@ fn bench_nfa_exponential(c: &mut Criterion) {
         ]),
     );
 
-    let nfa = ast.into_nfa();
+    let nfa = ast.into_nfa().unwrap();
 
     c.bench_function_over_inputs(
         "Backtracking NFA",
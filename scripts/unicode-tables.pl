@@ -0,0 +1,65 @@
+#!/usr/bin/env perl
+# Generates src/unicode_tables.rs from the Unicode database shipped with Perl:
+#
+#     perl scripts/unicode-tables.pl > src/unicode_tables.rs
+use strict;
+use warnings;
+use Unicode::UCD qw(prop_invlist prop_value_aliases prop_aliases charscripts);
+
+my @categories = qw(
+    L LC Lu Ll Lt Lm Lo M Mn Mc Me N Nd Nl No P Pc Pd Ps Pe Pi Pf Po
+    S Sm Sc Sk So Z Zs Zl Zp C Cc Cf Co Cn
+);
+my @binary = qw(Alphabetic Lowercase Uppercase White_Space Join_Control Math Emoji);
+
+# Inversion list to inclusive ranges, leaving out surrogates, which are not `char`s.
+sub ranges {
+    my @list = prop_invlist($_[0]);
+    push @list, 0x110000 if @list % 2;
+    my @ranges;
+    while (my ($lo, $end) = splice @list, 0, 2) {
+        my $hi = $end - 1;
+        if ($lo < 0xD800 && $hi >= 0xD800) {
+            push @ranges, [$lo, 0xD7FF];
+            $lo = 0xE000;
+        }
+        $lo = 0xE000 if $lo >= 0xD800 && $lo <= 0xDFFF;
+        push @ranges, [$lo, $hi] if $lo <= $hi;
+    }
+    return @ranges;
+}
+
+sub table {
+    my ($name, @entries) = @_;
+    print "pub const $name: Table = &[\n";
+    for my $entry (@entries) {
+        my ($names, $property) = @$entry;
+        my %seen;
+        my @names = map { "\"$_\"" } grep { !$seen{$_}++ } @$names;
+        print "    (&[", join(", ", @names), "], &[\n";
+        my $line = "       ";
+        for my $range (ranges($property)) {
+            my $item = sprintf(" ('\\u{%X}', '\\u{%X}'),", @$range);
+            if (length($line) + length($item) > 100) {
+                print "$line\n";
+                $line = "       ";
+            }
+            $line .= $item;
+        }
+        print "$line\n" if $line =~ /\S/;
+        print "    ]),\n";
+    }
+    print "];\n";
+}
+
+print "// Generated by scripts/unicode-tables.pl from Unicode ", Unicode::UCD::UnicodeVersion(), ", do not edit.\n\n";
+print "/// Names of a property, and its characters.\n";
+print "pub type Table = &'static [(&'static [&'static str], &'static [(char, char)])];\n\n";
+
+table("GENERAL_CATEGORY",
+    map { [[prop_value_aliases("gc", $_)], "gc=$_"] } @categories);
+print "\n";
+table("SCRIPT",
+    map { [[prop_value_aliases("sc", $_)], "sc=$_"] } sort keys %{charscripts()});
+print "\n";
+table("BINARY", map { [[prop_aliases($_)], $_] } @binary);
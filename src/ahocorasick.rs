@@ -0,0 +1,324 @@
+use ast::Token::{Concat, Epsilon, Literal, Or};
+use std::ascii;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use {Automaton, AST};
+
+use errors::*;
+
+/// Aho–Corasick automaton, finding every occurrence of a list of words in a single pass.
+///
+/// The words make a trie over their bytes, and each missing transition of a state is filled in
+/// with the transition of its failure link, the state of the longest proper suffix of its
+/// prefix that is a prefix of some word as well. The result is a DFA reading one byte per step,
+/// much cheaper to build than going through an NFA and subset construction. Bytes in no word
+/// share a class, so that the transition table only has a column for each byte of the words.
+///
+/// Words only ever end on character boundaries, since both they and the text are UTF-8.
+#[derive(Debug, Clone)]
+pub struct AhoCorasick {
+    words: Vec<String>,
+    /// Class of every byte, 0 for bytes in no word.
+    classes: Vec<usize>,
+    alphabet: usize,
+    /// Next state for a state and a class, at `state * alphabet + class`.
+    transitions: Vec<usize>,
+    /// Length of the prefix of a word each state stands for.
+    depths: Vec<usize>,
+    /// Words ending where each state is reached, longest first.
+    outputs: Vec<Vec<usize>>,
+}
+
+/// Transition of the trie that is not there yet.
+const MISSING: usize = usize::MAX;
+
+impl AhoCorasick {
+    pub fn new<I, S>(words: I) -> AhoCorasick
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let words: Vec<String> = words.into_iter().map(|w| w.as_ref().to_string()).collect();
+
+        let mut classes = vec![0; 256];
+        let mut alphabet = 1;
+        for word in &words {
+            for &b in word.as_bytes() {
+                if classes[b as usize] == 0 {
+                    classes[b as usize] = alphabet;
+                    alphabet += 1;
+                }
+            }
+        }
+
+        let mut transitions = vec![MISSING; alphabet];
+        let mut depths = vec![0];
+        let mut outputs = vec![vec![]];
+        for (index, word) in words.iter().enumerate() {
+            let mut state = 0;
+            for &b in word.as_bytes() {
+                let i = state * alphabet + classes[b as usize];
+                if transitions[i] == MISSING {
+                    transitions[i] = depths.len();
+                    transitions.resize(transitions.len() + alphabet, MISSING);
+                    depths.push(depths[state] + 1);
+                    outputs.push(vec![]);
+                }
+                state = transitions[i];
+            }
+            outputs[state].push(index);
+        }
+
+        // breadth first, so that failure links lead to states whose transitions are all there
+        let mut fail = vec![0; depths.len()];
+        let mut queue = VecDeque::new();
+        queue.push_back(0);
+        while let Some(state) = queue.pop_front() {
+            for class in 0..alphabet {
+                let fallback = if state == 0 {
+                    0
+                } else {
+                    transitions[fail[state] * alphabet + class]
+                };
+
+                let i = state * alphabet + class;
+                match transitions[i] {
+                    MISSING => transitions[i] = fallback,
+                    next => {
+                        fail[next] = fallback;
+                        let inherited = outputs[fallback].clone();
+                        outputs[next].extend(inherited);
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+
+        AhoCorasick {
+            words,
+            classes,
+            alphabet,
+            transitions,
+            depths,
+            outputs,
+        }
+    }
+
+    /// Automaton for a pattern that is an alternation of literal strings, like `if|else|while`,
+    /// with the words in the order of the alternatives.
+    pub fn from_ast(ast: &AST) -> Option<AhoCorasick> {
+        if *ast.token() != Or {
+            return None;
+        }
+
+        let words: Option<Vec<String>> = ast.children().iter().map(literal_string).collect();
+        words.map(AhoCorasick::new)
+    }
+
+    pub fn words(&self) -> &[String] {
+        &self.words
+    }
+
+    fn next_state(&self, state: usize, b: u8) -> usize {
+        self.transitions[state * self.alphabet + self.classes[b as usize]]
+    }
+
+    /// Checks if some word occurs in `s`.
+    pub fn is_match(&self, s: &str) -> bool {
+        let mut state = 0;
+        if !self.outputs[state].is_empty() {
+            return true;
+        }
+        for &b in s.as_bytes() {
+            state = self.next_state(state, b);
+            if !self.outputs[state].is_empty() {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Every occurrence of a word in `s`, overlapping ones included, as the index of the word
+    /// and its byte offsets, in the order of their ends, then of their starts.
+    pub fn find_overlapping(&self, s: &str) -> Vec<(usize, usize, usize)> {
+        let mut result = Vec::new();
+        let mut state = 0;
+        for end in 0..s.len() + 1 {
+            if end > 0 {
+                state = self.next_state(state, s.as_bytes()[end - 1]);
+            }
+            // only the empty word can end inside a character
+            if s.is_char_boundary(end) {
+                for &word in &self.outputs[state] {
+                    result.push((word, end - self.words[word].len(), end));
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Byte offsets of the leftmost occurrence of a word in `s`.
+    pub fn find(&self, s: &str) -> Option<(usize, usize)> {
+        self.find_at(s, 0)
+    }
+
+    /// Same as `find`, for an occurrence starting at byte offset `start` or later. Of the
+    /// words occurring there, the first one in the list wins, as it would for the alternation
+    /// of the words in a regex.
+    pub fn find_at(&self, s: &str, start: usize) -> Option<(usize, usize)> {
+        let longest = self.words.iter().map(String::len).max()?;
+        // start, word and end of the best occurrence so far
+        let mut best: Option<(usize, usize, usize)> = None;
+        let mut state = 0;
+        let mut end = start;
+        loop {
+            if s.is_char_boundary(end) {
+                for &word in &self.outputs[state] {
+                    let found = (end - self.words[word].len(), word, end);
+                    match best {
+                        Some(best) if (best.0, best.1) <= (found.0, found.1) => {}
+                        _ => best = Some(found),
+                    }
+                }
+            }
+
+            // later occurrences start after the best one
+            let done = best.is_some_and(|best| end >= best.0 + longest);
+            if done || end == s.len() {
+                return best.map(|(from, _, to)| (from, to));
+            }
+            state = self.next_state(state, s.as_bytes()[end]);
+            end += 1;
+        }
+    }
+}
+
+/// The string `ast` matches, if it is made of literal characters only.
+fn literal_string(ast: &AST) -> Option<String> {
+    match *ast.token() {
+        Literal(c) => Some(c.to_string()),
+        Epsilon => Some(String::new()),
+        Concat => ast
+            .children()
+            .iter()
+            .map(|child| match *child.token() {
+                Literal(c) => Some(c),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+impl Automaton for AhoCorasick {
+    /// Checks if `s` is one of the words, like the alternation of the words would.
+    fn run(&self, s: &str) -> bool {
+        let state = s
+            .as_bytes()
+            .iter()
+            .fold(0, |state, &b| self.next_state(state, b));
+
+        // a state deeper than the input would have been reached through failure links
+        self.depths[state] == s.len()
+            && self.outputs[state]
+                .first()
+                .is_some_and(|&word| self.words[word].len() == s.len())
+    }
+
+    /// Writes the trie, leaving out the transitions added for failure links.
+    fn write_graphviz(&self, filename: &str) -> Result<()> {
+        let path = Path::new(filename);
+        let mut file = File::create(path).chain_err(|| "unable to create file")?;
+
+        writeln!(&mut file, "digraph aho_corasick {{").chain_err(|| "")?;
+        writeln!(&mut file, "\trankdir=LR;").chain_err(|| "")?;
+        write!(&mut file, "\tnode [shape = doublecircle]; ").chain_err(|| "")?;
+        for (state, outputs) in self.outputs.iter().enumerate() {
+            if !outputs.is_empty() {
+                write!(&mut file, "{} ", state).chain_err(|| "")?;
+            }
+        }
+        writeln!(&mut file, ";\n\tnode [shape = circle];").chain_err(|| "")?;
+        for state in 0..self.depths.len() {
+            for b in 0..256 {
+                if self.classes[b] == 0 {
+                    continue;
+                }
+                let next = self.next_state(state, b as u8);
+                if self.depths[next] == self.depths[state] + 1 {
+                    let label: String = ascii::escape_default(b as u8).map(char::from).collect();
+                    writeln!(
+                        &mut file,
+                        "\t{} -> {} [ label = \"{}\"]",
+                        state,
+                        next,
+                        label.escape_default()
+                    )
+                    .chain_err(|| "")?;
+                }
+            }
+        }
+
+        writeln!(&mut file, "}}").chain_err(|| "")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_overlapping() {
+        let ac = AhoCorasick::new(["he", "she", "his", "hers"]);
+        assert_eq!(
+            ac.find_overlapping("ushers"),
+            vec![(1, 1, 4), (0, 2, 4), (3, 2, 6)]
+        );
+        assert!(ac.is_match("ahisb"));
+        assert!(!ac.is_match("hi, sh"));
+        assert!(ac.find_overlapping("").is_empty());
+
+        let ac = AhoCorasick::new(["é", "", "aé"]);
+        assert_eq!(
+            ac.find_overlapping("aé"),
+            vec![(1, 0, 0), (1, 1, 1), (2, 0, 3), (0, 1, 3), (1, 3, 3)]
+        );
+        assert!(ac.is_match("x"));
+    }
+
+    #[test]
+    fn find() {
+        let ac = AhoCorasick::new(["abcd", "bc", "b", "abc"]);
+        assert_eq!(ac.find("xabcd"), Some((1, 5)));
+        assert_eq!(ac.find("xabce"), Some((1, 4)));
+        assert_eq!(ac.find("xbcd"), Some((1, 3)));
+        assert_eq!(ac.find_at("xabcd", 2), Some((2, 4)));
+        assert_eq!(ac.find("xyz"), None);
+        assert_eq!(AhoCorasick::new(Vec::<String>::new()).find("a"), None);
+        assert_eq!(AhoCorasick::new(["a", ""]).find("ba"), Some((0, 0)));
+    }
+
+    #[test]
+    fn run() {
+        let ac = AhoCorasick::from_ast(&AST::parse("if|else|elsif|").unwrap()).unwrap();
+        assert_eq!(ac.words(), ["if", "else", "elsif", ""]);
+        assert!(ac.run("else") && ac.run("elsif") && ac.run(""));
+        assert!(!ac.run("els") && !ac.run("sif") && !ac.run("elsee"));
+
+        assert!(AhoCorasick::from_ast(&AST::parse("ab").unwrap()).is_none());
+        assert!(AhoCorasick::from_ast(&AST::parse("a|b+").unwrap()).is_none());
+        assert!(AhoCorasick::from_ast(&AST::parse("(?i)a|b").unwrap()).is_none());
+    }
+
+    #[ignore]
+    #[test]
+    fn graphviz() -> Result<()> {
+        AhoCorasick::new(["he", "she", "his", "hers", "é"]).write_graphviz("graphs/ahocorasick.dot")
+    }
+}
@@ -0,0 +1,247 @@
+use std::fmt;
+use CharClass;
+
+/// Zero-width assertions, which look at the characters around the current position without
+/// consuming any of them.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, PartialOrd, Ord, Hash)]
+pub enum Assertion {
+    /// `\A`, and `^` outside of multi-line mode.
+    StartText,
+    /// `\z`, and `$` outside of multi-line mode.
+    EndText,
+    /// `^` in multi-line mode: start of the input or right after `\n`.
+    StartLine,
+    /// `$` in multi-line mode: end of the input or right before `\n`.
+    EndLine,
+    /// `\b`: a word character on exactly one side.
+    WordBoundary,
+    /// `\B`: word characters on both sides or on neither.
+    NotWordBoundary,
+    /// `\b` in ASCII-only mode, where word characters are `[0-9A-Za-z_]`.
+    AsciiWordBoundary,
+    /// `\B` in ASCII-only mode.
+    AsciiNotWordBoundary,
+}
+
+/// Lookaround groups match an empty string, depending on whether their pattern matches right
+/// after or right before the position.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, PartialOrd, Ord, Hash)]
+pub enum Look {
+    /// `(?=...)`
+    Ahead,
+    /// `(?!...)`
+    NotAhead,
+    /// `(?<=...)`
+    Behind,
+    /// `(?<!...)`
+    NotBehind,
+}
+
+impl Look {
+    pub fn is_ahead(&self) -> bool {
+        *self == Look::Ahead || *self == Look::NotAhead
+    }
+
+    pub fn is_negated(&self) -> bool {
+        *self == Look::NotAhead || *self == Look::NotBehind
+    }
+}
+
+/// Bits describing the character before the current position, which is all that automata
+/// need to remember to check assertions: unlike the character after, it is gone once consumed.
+pub type LookBehind = u8;
+
+pub const AT_START: LookBehind = 1;
+pub const AFTER_NEWLINE: LookBehind = 1 << 1;
+pub const AFTER_WORD: LookBehind = 1 << 2;
+pub const AFTER_ASCII_WORD: LookBehind = 1 << 3;
+
+/// Every value `look_behind` can return, which automata need a start state for.
+pub const CONTEXTS: [LookBehind; 5] = [
+    AT_START,
+    AFTER_NEWLINE,
+    AFTER_WORD | AFTER_ASCII_WORD,
+    AFTER_WORD,
+    0,
+];
+
+pub fn look_behind(previous: Option<char>) -> LookBehind {
+    match previous {
+        None => AT_START,
+        Some('\n') => AFTER_NEWLINE,
+        Some(c) if is_ascii_word_char(c) => AFTER_WORD | AFTER_ASCII_WORD,
+        Some(c) if is_word_char(c) => AFTER_WORD,
+        Some(_) => 0,
+    }
+}
+
+/// Checks if `c` is in `\w`, in Unicode mode.
+pub fn is_word_char(c: char) -> bool {
+    if c.is_ascii() {
+        is_ascii_word_char(c)
+    } else {
+        CharClass::word().contains(c)
+    }
+}
+
+/// Checks if `c` is in `\w`, in ASCII-only mode.
+pub fn is_ascii_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+impl Assertion {
+    pub fn holds(&self, previous: Option<char>, next: Option<char>) -> bool {
+        self.holds_after(look_behind(previous), next)
+    }
+
+    /// Checks the assertion at position `pos` of `chars`, counted in characters.
+    pub fn holds_at(&self, chars: &[char], pos: usize) -> bool {
+        self.holds(
+            pos.checked_sub(1).map(|i| chars[i]),
+            chars.get(pos).cloned(),
+        )
+    }
+
+    pub fn holds_after(&self, behind: LookBehind, next: Option<char>) -> bool {
+        match *self {
+            Assertion::StartText => behind & AT_START != 0,
+            Assertion::EndText => next.is_none(),
+            Assertion::StartLine => behind & (AT_START | AFTER_NEWLINE) != 0,
+            Assertion::EndLine => next.is_none() || next == Some('\n'),
+            Assertion::WordBoundary => (behind & AFTER_WORD != 0) != next.is_some_and(is_word_char),
+            Assertion::NotWordBoundary => {
+                (behind & AFTER_WORD != 0) == next.is_some_and(is_word_char)
+            }
+            Assertion::AsciiWordBoundary => {
+                (behind & AFTER_ASCII_WORD != 0) != next.is_some_and(is_ascii_word_char)
+            }
+            Assertion::AsciiNotWordBoundary => {
+                (behind & AFTER_ASCII_WORD != 0) == next.is_some_and(is_ascii_word_char)
+            }
+        }
+    }
+
+    /// The same assertion for the reversed input, where what comes before and after are
+    /// swapped.
+    pub fn reverse(&self) -> Assertion {
+        match *self {
+            Assertion::StartText => Assertion::EndText,
+            Assertion::EndText => Assertion::StartText,
+            Assertion::StartLine => Assertion::EndLine,
+            Assertion::EndLine => Assertion::StartLine,
+            assertion => assertion,
+        }
+    }
+
+    /// The bits of `LookBehind` the assertion depends on. Automata only need to remember those
+    /// for the assertions they contain, which keeps them from splitting states for nothing.
+    pub fn looks_behind(&self) -> LookBehind {
+        match *self {
+            Assertion::StartText => AT_START,
+            Assertion::StartLine => AT_START | AFTER_NEWLINE,
+            Assertion::EndText | Assertion::EndLine => 0,
+            Assertion::WordBoundary | Assertion::NotWordBoundary => AFTER_WORD,
+            Assertion::AsciiWordBoundary | Assertion::AsciiNotWordBoundary => AFTER_ASCII_WORD,
+        }
+    }
+
+    /// Characters the assertion has to tell apart from all others when they come right after
+    /// the position, and so also right before it once consumed.
+    pub fn looks_at(&self) -> CharClass {
+        match *self {
+            Assertion::StartText | Assertion::EndText => CharClass::new(vec![]),
+            Assertion::StartLine | Assertion::EndLine => CharClass::new(vec![('\n', '\n')]),
+            Assertion::WordBoundary | Assertion::NotWordBoundary => CharClass::word().clone(),
+            Assertion::AsciiWordBoundary | Assertion::AsciiNotWordBoundary => {
+                CharClass::perl('w', true).unwrap()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Assertion::StartText => "\\A",
+            Assertion::EndText => "\\z",
+            Assertion::StartLine => "^",
+            Assertion::EndLine => "$",
+            Assertion::WordBoundary => "\\b",
+            Assertion::NotWordBoundary => "\\B",
+            Assertion::AsciiWordBoundary => "(?a:\\b)",
+            Assertion::AsciiNotWordBoundary => "(?a:\\B)",
+        };
+        f.write_str(s)
+    }
+}
+
+impl fmt::Display for Look {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Look::Ahead => "(?=",
+            Look::NotAhead => "(?!",
+            Look::Behind => "(?<=",
+            Look::NotBehind => "(?<!",
+        };
+        f.write_str(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds() {
+        assert!(Assertion::StartText.holds(None, Some('a')));
+        assert!(!Assertion::StartText.holds(Some('\n'), Some('a')));
+        assert!(Assertion::StartLine.holds(Some('\n'), Some('a')));
+        assert!(Assertion::EndText.holds(Some('a'), None));
+        assert!(!Assertion::EndText.holds(Some('a'), Some('\n')));
+        assert!(Assertion::EndLine.holds(Some('a'), Some('\n')));
+        assert!(!Assertion::EndLine.holds(Some('a'), Some('b')));
+    }
+
+    #[test]
+    fn word_boundaries() {
+        assert!(Assertion::WordBoundary.holds(None, Some('a')));
+        assert!(Assertion::WordBoundary.holds(Some('ä'), Some(' ')));
+        assert!(!Assertion::WordBoundary.holds(Some('a'), Some('_')));
+        assert!(!Assertion::WordBoundary.holds(None, None));
+        assert!(Assertion::NotWordBoundary.holds(Some('-'), Some(' ')));
+        assert!(Assertion::NotWordBoundary.holds(Some('1'), Some('x')));
+        assert!(!Assertion::NotWordBoundary.holds(Some('x'), None));
+        assert!(Assertion::AsciiWordBoundary.holds(Some('a'), Some('é')));
+        assert!(!Assertion::AsciiNotWordBoundary.holds(Some('a'), Some('é')));
+        assert!(Assertion::AsciiNotWordBoundary.holds(Some('é'), Some(' ')));
+        // a combining mark is part of the word, like for `\w`
+        assert!(!Assertion::WordBoundary.holds(Some('e'), Some('\u{301}')));
+        assert!(!Assertion::WordBoundary.holds(Some('\u{301}'), Some('x')));
+    }
+
+    #[test]
+    fn reverse() {
+        let around = [None, Some('a'), Some('\n'), Some(' ')];
+        for &assertion in &[
+            Assertion::StartText,
+            Assertion::EndText,
+            Assertion::StartLine,
+            Assertion::EndLine,
+            Assertion::WordBoundary,
+            Assertion::NotWordBoundary,
+            Assertion::AsciiWordBoundary,
+            Assertion::AsciiNotWordBoundary,
+        ] {
+            for &previous in &around {
+                for &next in &around {
+                    assert_eq!(
+                        assertion.reverse().holds(next, previous),
+                        assertion.holds(previous, next),
+                        "{}",
+                        assertion
+                    );
+                }
+            }
+        }
+    }
+}
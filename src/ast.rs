@@ -1,26 +1,51 @@
 use ast::Token::*;
-use {State, Transition, NFA};
+use {Assertion, CharClass, Compiler, Look, Program, NFA};
 
+use errors::Result;
+
+#[allow(unexpected_cfgs)]
 mod errors {
     error_chain!{}
 }
 
-use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct AST {
     token: Token,
     children: Option<Vec<AST>>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Token {
     Concat,
     Or,
     Star,
+    /// Same as `Star`, but prefers leaving the loop to doing one more iteration.
+    LazyStar,
+    /// One or more repetitions of its only child, which unlike `r r*` doesn't need a copy of
+    /// the child.
+    Plus,
+    /// Same as `Plus`, but prefers leaving the loop to doing one more iteration.
+    LazyPlus,
     Literal(char),
+    Class(CharClass),
+    /// Matches an empty string, but only where the assertion holds.
+    Assertion(Assertion),
+    /// Matches an empty string where its only child does (or doesn't) match right after or
+    /// right before.
+    Look(Look),
+    /// Capturing group with its number, counted from 1, and its name if it has one.
+    Group(u32, Option<String>),
+    /// Matches the same text as the last match of the group with this number, in any case if
+    /// the flag is set.
+    Backref(u32, bool),
+    /// Matches what its only child matches, ignoring case. Compilers expand it with
+    /// `AST::fold_case`.
+    CaseInsensitive,
     Epsilon,
+    /// Matches nothing at all, not even an empty string.
+    Empty,
 }
 
 impl AST {
@@ -28,261 +53,254 @@ impl AST {
         AST { token, children }
     }
 
-    pub fn into_nfa(self) -> NFA {
-        match self.token {
-            Concat => AST::concat_nfa(
-                self.children
-                    .unwrap()
-                    .into_iter()
-                    .map(|x| x.into_nfa())
-                    .collect(),
-            ),
-            Or => AST::or_nfa(
-                self.children
-                    .unwrap()
-                    .into_iter()
-                    .map(|x| x.into_nfa())
-                    .collect(),
-            ),
-            Star => AST::star_nfa(
-                self.children
-                    .unwrap()
-                    .into_iter()
-                    .nth(0)
-                    .unwrap()
-                    .into_nfa(),
-            ),
-            Literal(c) => AST::literal_nfa(c),
-            Epsilon => AST::epsilon_nfa(),
+    pub fn token(&self) -> &Token {
+        &self.token
+    }
+
+    pub fn children(&self) -> &[AST] {
+        match self.children {
+            Some(ref children) => children,
+            None => &[],
         }
     }
 
-    fn concat_nfa(nfas: Vec<NFA>) -> NFA {
-        nfas.into_iter().fold(NFA::default(), |mut acc, mut x| {
-            let max_state = *acc.states().iter().max().unwrap();
-            AST::increase_nfa_states(&mut x, max_state + 1);
-
-            for ((s, t), ns) in x.transitions {
-                if s == x.start {
-                    for f in &acc.accept {
-                        acc.transitions
-                            .entry((*f, t))
-                            .or_insert(btreeset!())
-                            .extend(&ns);
-                    }
-                } else {
-                    acc.transitions.insert((s, t), ns);
-                }
-            }
+    pub fn literal(c: char) -> AST {
+        AST::new(Literal(c), None)
+    }
 
-            acc.accept = x.accept;
+    pub fn assertion(assertion: Assertion) -> AST {
+        AST::new(Token::Assertion(assertion), None)
+    }
 
-            acc
-        })
+    pub fn look(look: Look, child: AST) -> AST {
+        AST::new(Token::Look(look), Some(vec![child]))
     }
 
-    fn or_nfa(mut nfas: Vec<NFA>) -> NFA {
-        let mut max_state = -1;
-        let mut old_starts = BTreeSet::new();
-        let mut old_accepts: BTreeSet<State> = BTreeSet::new();
+    pub fn group(index: u32, name: Option<String>, child: AST) -> AST {
+        AST::new(Group(index, name), Some(vec![child]))
+    }
 
-        for nfa in &mut nfas {
-            AST::increase_nfa_states(nfa, max_state + 1);
-            old_starts.insert(nfa.start);
-            old_accepts.extend(nfa.accept.iter());
-            max_state = *nfa.states().iter().max().unwrap();
-        }
+    pub fn backref(index: u32) -> AST {
+        AST::new(Backref(index, false), None)
+    }
 
-        let start = max_state + 1;
-        let accept = max_state + 2;
-        let mut transitions = BTreeMap::new();
+    pub fn case_insensitive(child: AST) -> AST {
+        AST::new(CaseInsensitive, Some(vec![child]))
+    }
 
-        for nfa in nfas {
-            transitions.extend(nfa.transitions);
-        }
+    pub fn epsilon() -> AST {
+        AST::new(Epsilon, None)
+    }
+
+    pub fn empty() -> AST {
+        AST::new(Empty, None)
+    }
 
-        transitions.insert((start, Transition::Epsilon), old_starts);
-        for a in old_accepts {
-            transitions
-                .entry((a, Transition::Epsilon))
-                .or_insert(btreeset!())
-                .insert(accept);
+    /// Smart constructor for concatenation: nested concatenations are flattened, epsilons are
+    /// dropped, and anything concatenated with `Empty` becomes `Empty`.
+    pub fn concat(children: Vec<AST>) -> AST {
+        let mut result = Vec::new();
+        for child in children {
+            match child.token {
+                Empty => return AST::empty(),
+                Epsilon => {}
+                Concat => result.extend(child.children.unwrap()),
+                _ => result.push(child),
+            }
         }
 
-        NFA::new(start, btreeset!(accept), transitions)
+        match result.len() {
+            0 => AST::epsilon(),
+            1 => result.pop().unwrap(),
+            _ => AST::new(Concat, Some(result)),
+        }
     }
 
-    fn star_nfa(mut nfa: NFA) -> NFA {
-        let max_state = *nfa.states().iter().max().unwrap();
+    /// Smart constructor for alternation: nested alternations are flattened, `Empty` branches are
+    /// dropped, and the rest are sorted and deduplicated, so that `a|b` and `b|a|b` end up being
+    /// the same tree. The order of alternatives is lost, so engines that give priority to the
+    /// leftmost alternative need trees built with `AST::new` instead.
+    pub fn or(children: Vec<AST>) -> AST {
+        let mut result = BTreeSet::new();
+        for child in children {
+            match child.token {
+                Empty => {}
+                Or => result.extend(child.children.unwrap()),
+                _ => {
+                    result.insert(child);
+                }
+            }
+        }
 
-        let new_start = max_state + 1;
-        let new_accept = max_state + 2;
+        let mut result: Vec<AST> = result.into_iter().collect();
+        match result.len() {
+            0 => AST::empty(),
+            1 => result.pop().unwrap(),
+            _ => AST::new(Or, Some(result)),
+        }
+    }
 
-        nfa.transitions.insert(
-            (new_start, Transition::Epsilon),
-            btreeset!(nfa.start, new_accept),
-        );
-        for s in &nfa.accept {
-            nfa.transitions
-                .entry((*s, Transition::Epsilon))
-                .or_insert(btreeset!())
-                .extend(btreeset!(nfa.start, new_accept));
+    /// Smart constructor for Kleene star: `(r*)*` is `r*`, and both `ε*` and `∅*` are `ε`.
+    pub fn star(child: AST) -> AST {
+        match child.token {
+            Star => child,
+            Epsilon | Empty => AST::epsilon(),
+            _ => AST::new(Star, Some(vec![child])),
         }
+    }
 
-        nfa.start = new_start;
-        nfa.accept = btreeset!(new_accept);
+    /// Smart constructor for one or more repetitions: `(r+)+` is `r+`, `(r*)+` is `r*`, and
+    /// `ε+` and `∅+` are `ε` and `∅`.
+    pub fn plus(child: AST) -> AST {
+        match child.token {
+            Plus | Star | Epsilon | Empty => child,
+            _ => AST::new(Plus, Some(vec![child])),
+        }
+    }
 
-        nfa
+    /// Rebuilds the tree bottom-up using smart constructors. Lazy loops become greedy and groups
+    /// are dropped, as neither changes the language.
+    pub fn normalize(&self) -> AST {
+        let children = self.children().iter().map(|x| x.normalize()).collect();
+        match self.token {
+            Concat => AST::concat(children),
+            Or => AST::or(children),
+            Star | LazyStar => AST::star(children.into_iter().nth(0).unwrap()),
+            Plus | LazyPlus => AST::plus(children.into_iter().nth(0).unwrap()),
+            Token::Look(look) => AST::look(look, children.into_iter().nth(0).unwrap()),
+            Group(..) => children.into_iter().nth(0).unwrap(),
+            CaseInsensitive => children.into_iter().nth(0).unwrap().fold_case(),
+            _ => self.clone(),
+        }
     }
 
-    fn literal_nfa(c: char) -> NFA {
-        NFA::new(
-            0,
-            btreeset!(1),
-            btreemap!(
-                (0, Transition::Character(c)) => btreeset!(1)
+    /// Turns every literal and class of the tree into a class with all cases of its characters,
+    /// see `CharClass::fold_case`, and every backreference into one ignoring case.
+    pub fn fold_case(&self) -> AST {
+        match self.token {
+            Literal(c) => {
+                let class = CharClass::new(vec![(c, c)]).fold_case();
+                if class.ranges() == [(c, c)] {
+                    self.clone()
+                } else {
+                    AST::new(Class(class), None)
+                }
+            }
+            Class(ref class) => AST::new(Class(class.fold_case()), None),
+            Backref(index, _) => AST::new(Backref(index, true), None),
+            CaseInsensitive => self.children()[0].fold_case(),
+            _ => AST::new(
+                self.token.clone(),
+                self.children
+                    .as_ref()
+                    .map(|children| children.iter().map(|x| x.fold_case()).collect()),
             ),
-        )
+        }
     }
 
-    fn epsilon_nfa() -> NFA {
-        NFA::new(
-            0,
-            btreeset!(1),
-            btreemap!(
-                (0, Transition::Epsilon) => btreeset!(1)
-            ),
-        )
+    /// Builds a Thompson NFA, see `Compiler` for other constructions.
+    pub fn into_nfa(self) -> Result<NFA> {
+        Compiler::new().compile(self)
     }
 
-    /// In order to use two different NFAs together, we can increase ids of states of one of them,
-    /// so that all of states of first NFA will be lower than some N, and of the second will be
-    /// higher than that N.
-    ///
-    /// This way they will coexist without conflicts, and additional glue can be added easily.
-    fn increase_nfa_states(nfa: &mut NFA, n: State) {
-        nfa.start += n;
-        nfa.accept = nfa.accept.iter().map(|x| *x + n).collect();
-
-        let mut new_transitions = BTreeMap::new();
-        for ((k, t), v) in &nfa.transitions {
-            let new_value = v.iter().map(|x| x + n).collect();
-            new_transitions.insert((k + n, *t), new_value);
-        }
-        nfa.transitions = new_transitions;
+    /// Builds the program of a Thompson NFA, backreferences included.
+    pub fn into_program(self) -> Program {
+        Compiler::new().compile_program(self)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::{Duration, Instant};
+    use {Automaton, Transition};
 
     #[test]
-    fn literal_nfa() {
-        let nfa = AST::literal_nfa('a');
+    fn smart_constructors() {
+        let a = AST::literal('a');
+        let b = AST::literal('b');
 
+        assert_eq!(AST::concat(vec![AST::epsilon(), a.clone()]), a);
+        assert_eq!(AST::concat(vec![a.clone(), AST::empty()]), AST::empty());
         assert_eq!(
-            nfa,
-            NFA::new(
-                0,
-                btreeset!(1),
-                btreemap!((0, Transition::Character('a')) => btreeset ! (1)),
-            )
-        );
-    }
-
-    #[test]
-    fn increase_nfa_states() {
-        let mut nfa = AST::literal_nfa('a');
-        AST::increase_nfa_states(&mut nfa, 5);
-
-        assert_eq!(
-            nfa,
-            NFA::new(
-                5,
-                btreeset!(6),
-                btreemap!((5, Transition::Character('a')) => btreeset ! (6)),
-            )
+            AST::or(vec![
+                b.clone(),
+                a.clone(),
+                AST::or(vec![b.clone(), AST::empty()])
+            ]),
+            AST::new(Or, Some(vec![a.clone(), b.clone()]))
         );
+        assert_eq!(AST::star(AST::star(a.clone())), AST::star(a.clone()));
+        assert_eq!(AST::star(AST::empty()), AST::epsilon());
+        assert_eq!(AST::plus(AST::plus(a.clone())), AST::plus(a.clone()));
+        assert_eq!(AST::plus(AST::star(a.clone())), AST::star(a.clone()));
     }
 
     #[test]
-    fn star_nfa() {
-        let nfa = NFA::new(
-            0,
-            btreeset!(1),
-            btreemap!((0, Transition::Character('a')) => btreeset! (1)),
-        );
+    fn fold_case() {
+        let ast = AST::case_insensitive(AST::concat(vec![
+            AST::literal('a'),
+            AST::literal('1'),
+            AST::new(Class(CharClass::new(vec![('x', 'y')])), None),
+        ]));
 
         assert_eq!(
-            AST::star_nfa(nfa),
-            NFA::new(
-                2,
-                btreeset!(3),
-                btreemap!(
-                    (0, Transition::Character('a')) => btreeset ! (1),
-                    (1, Transition::Epsilon) => btreeset !(0, 3),
-                    (2, Transition::Epsilon) => btreeset ! (0, 3),
-                ),
-            )
+            ast.fold_case(),
+            AST::concat(vec![
+                AST::new(Class(CharClass::new(vec![('A', 'A'), ('a', 'a')])), None),
+                AST::literal('1'),
+                AST::new(Class(CharClass::new(vec![('X', 'Y'), ('x', 'y')])), None),
+            ])
         );
     }
 
     #[test]
-    fn or_nfa() {
-        let mut nfas = Vec::new();
-
-        nfas.push(NFA::new(
-            0,
-            btreeset!(1),
-            btreemap!((0, Transition::Character('a')) => btreeset ! (1)),
-        ));
-
-        nfas.push(NFA::new(
-            0,
-            btreeset!(1),
-            btreemap!((0, Transition::Character('b')) => btreeset ! (1)),
-        ));
-
-        assert_eq!(
-            AST::or_nfa(nfas),
-            NFA::new(
-                4,
-                btreeset!(5),
-                btreemap!(
-                    (0, Transition::Character('a')) => btreeset ! (1),
-                    (1, Transition::Epsilon) => btreeset ! (5),
-                    (2, Transition::Character('b')) => btreeset ! (3),
-                    (3, Transition::Epsilon) => btreeset ! (5),
-                    (4, Transition::Epsilon) => btreeset ! (0, 2),
-                ),
-            )
+    fn to_nfa() {
+        let ast = AST::new(
+            Or,
+            Some(vec![
+                AST::new(Literal('a'), None),
+                AST::concat(vec![AST::literal('b'), AST::star(AST::literal('c'))]),
+            ]),
         );
-    }
+        let nfa = ast.into_nfa().unwrap();
 
-    #[test]
-    fn concat_nfa() {
         assert_eq!(
+            nfa,
             NFA::new(
                 0,
-                btreeset!(4),
+                btreeset!(8),
                 btreemap!(
-                    (0, Transition::Character('a')) => btreeset!(2),
-                    (2, Transition::Character('b')) => btreeset!(4),
+                    (0, Transition::Epsilon) => btreeset!(1, 3),
+                    (1, Transition::Character('a')) => btreeset!(2),
+                    (2, Transition::Epsilon) => btreeset!(8),
+                    (3, Transition::Character('b')) => btreeset!(4),
+                    (4, Transition::Epsilon) => btreeset!(5, 7),
+                    (5, Transition::Character('c')) => btreeset!(6),
+                    (6, Transition::Epsilon) => btreeset!(5, 7),
+                    (7, Transition::Epsilon) => btreeset!(8),
                 ),
-            ),
-            AST::concat_nfa(vec![AST::literal_nfa('a'), AST::literal_nfa('b')])
+            )
         );
+        for s in &["a", "b", "bc", "bccc"] {
+            assert!(nfa.run(s), "{}", s);
+        }
+        for s in &["", "c", "ab", "ac", "bcb"] {
+            assert!(!nfa.run(s), "{}", s);
+        }
     }
 
     #[test]
-    fn to_nfa() {
-        let _ast = AST::new(
-            Or,
-            Some(vec![
-                AST::new(Literal('a'), None),
-                AST::new(Literal('b'), None),
-            ]),
+    fn to_nfa_is_linear() {
+        // gluing fragments used to renumber everything built so far, which took quadratic time
+        let ast = AST::concat(
+            (0..50_000)
+                .map(|_| AST::or(vec![AST::literal('a'), AST::literal('b')]))
+                .collect(),
         );
+        let start = Instant::now();
+        let nfa = ast.into_nfa().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(nfa.states().len(), 250_001);
     }
 }
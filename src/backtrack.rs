@@ -0,0 +1,446 @@
+use class::eq_ignoring_case;
+use fnv::FnvHashSet;
+use program::{Haystack, Inst, InstPtr};
+use Program;
+
+/// A set of `(instruction, position)` pairs, one bit per pair.
+#[derive(Debug, Clone)]
+struct Visited {
+    bits: Vec<u64>,
+    stride: usize,
+    /// Words with some bit set, so that clearing only goes over those.
+    touched: Vec<usize>,
+}
+
+impl Visited {
+    fn new(insts: usize, positions: usize) -> Visited {
+        Visited {
+            bits: vec![0; (insts * positions).div_ceil(64)],
+            stride: positions,
+            touched: Vec::new(),
+        }
+    }
+
+    /// Returns `false` if the pair was already visited.
+    fn insert(&mut self, pc: u32, pos: usize) -> bool {
+        let key = pc as usize * self.stride + pos;
+        let (word, bit) = (key / 64, 1 << (key % 64));
+        if self.bits[word] & bit != 0 {
+            return false;
+        }
+
+        if self.bits[word] == 0 {
+            self.touched.push(word);
+        }
+        self.bits[word] |= bit;
+        true
+    }
+
+    fn clear(&mut self) {
+        for &word in &self.touched {
+            self.bits[word] = 0;
+        }
+        self.touched.clear();
+    }
+}
+
+/// Explored `(instruction, position)` pairs, together with the values of the capture slots
+/// backreferences read, for programs with backreferences: the same pair can then succeed or
+/// fail depending on what the groups captured, but not when those are the same.
+#[derive(Debug, Clone)]
+struct VisitedWithSlots {
+    slots: Vec<usize>,
+    set: FnvHashSet<(InstPtr, usize, Vec<Option<usize>>)>,
+}
+
+impl VisitedWithSlots {
+    fn new(program: &Program) -> VisitedWithSlots {
+        let mut slots: Vec<usize> = program
+            .insts
+            .iter()
+            .filter_map(|inst| match *inst {
+                Inst::Backref(group, ..) => Some(group as usize),
+                _ => None,
+            })
+            .flat_map(|group| vec![2 * group, 2 * group + 1])
+            .collect();
+        slots.sort();
+        slots.dedup();
+
+        VisitedWithSlots {
+            slots,
+            set: FnvHashSet::default(),
+        }
+    }
+
+    /// Returns `false` if the pair was already visited with the same values in `slots`.
+    fn insert(&mut self, pc: InstPtr, pos: usize, slots: &[Option<usize>]) -> bool {
+        let values = self.slots.iter().map(|&slot| slots[slot]).collect();
+        self.set.insert((pc, pos, values))
+    }
+}
+
+/// Work left on the stack of a search.
+#[derive(Debug, Clone)]
+enum Frame {
+    Explore(InstPtr, usize),
+    /// Puts back the previous value of a capture slot, when backtracking past a `Save`.
+    Restore(usize, Option<usize>),
+}
+
+/// Buffers of depth-first searches over a program, with positions counted in characters. They
+/// are kept from one search to the next, so that searching for successive matches in the same
+/// text doesn't allocate them again.
+#[derive(Debug, Clone)]
+pub struct Backtracker {
+    /// Missing when the program has backreferences, see `find_backtracking`, and until the
+    /// first search.
+    visited: Option<Visited>,
+    /// Only there when the program has backreferences.
+    visited_with_slots: Option<VisitedWithSlots>,
+    stack: Vec<Frame>,
+    slots: Vec<Option<usize>>,
+}
+
+impl Backtracker {
+    pub fn new(program: &Program) -> Backtracker {
+        Backtracker {
+            visited: None,
+            visited_with_slots: if program.has_backrefs() {
+                Some(VisitedWithSlots::new(program))
+            } else {
+                None
+            },
+            stack: Vec::new(),
+            slots: vec![None; program.slots()],
+        }
+    }
+
+    /// Forgets everything earlier searches explored and captured, before searching `haystack`.
+    fn reset(&mut self, program: &Program, haystack: &Haystack) {
+        for slot in &mut self.slots {
+            *slot = None;
+        }
+        if let Some(ref mut visited) = self.visited_with_slots {
+            visited.set.clear();
+            return;
+        }
+
+        let positions = haystack.chars.len() + 1;
+        match self.visited {
+            Some(ref mut visited) if visited.stride == positions => visited.clear(),
+            _ => self.visited = Some(Visited::new(program.insts.len(), positions)),
+        }
+    }
+
+    /// Byte offsets of the groups of the first match found from byte offset `start` on, see
+    /// `Program::captures_backtracking`.
+    pub fn captures(
+        &mut self,
+        program: &Program,
+        haystack: &Haystack,
+        start: usize,
+    ) -> Option<Vec<Option<(usize, usize)>>> {
+        self.reset(program, haystack);
+        let offsets = &haystack.offsets;
+        for start in haystack.position(start)..offsets.len() {
+            if let Some(end) = self.search(program, haystack, start, false) {
+                self.slots[0] = Some(start);
+                self.slots[1] = Some(end);
+                return Some(
+                    self.slots
+                        .chunks(2)
+                        .map(|slots| match (slots[0], slots[1]) {
+                            (Some(from), Some(to)) => Some((offsets[from], offsets[to])),
+                            _ => None,
+                        })
+                        .collect(),
+                );
+            }
+        }
+        None
+    }
+
+    /// Returns the end of the first match starting at `start` that is found, or only of those
+    /// reaching the end of the input if `full` is set. Capture slots are left as that match set
+    /// them.
+    fn search(
+        &mut self,
+        program: &Program,
+        haystack: &Haystack,
+        start: usize,
+        full: bool,
+    ) -> Option<usize> {
+        let chars = &haystack.chars;
+        self.stack.push(Frame::Explore(program.start, start));
+        while let Some(frame) = self.stack.pop() {
+            let (pc, pos) = match frame {
+                Frame::Explore(pc, pos) => (pc, pos),
+                Frame::Restore(slot, value) => {
+                    self.slots[slot] = value;
+                    continue;
+                }
+            };
+            if let Some(ref mut visited) = self.visited {
+                if !visited.insert(pc, pos) {
+                    continue;
+                }
+            }
+            if let Some(ref mut visited) = self.visited_with_slots {
+                if !visited.insert(pc, pos, &self.slots) {
+                    continue;
+                }
+            }
+
+            match program.insts[pc as usize] {
+                Inst::Match => {
+                    if !full || pos == chars.len() {
+                        self.stack.clear();
+                        return Some(pos);
+                    }
+                }
+                Inst::Fail => {}
+                Inst::Jump(next) => self.stack.push(Frame::Explore(next, pos)),
+                Inst::Split(x, y) => {
+                    self.stack.push(Frame::Explore(y, pos));
+                    self.stack.push(Frame::Explore(x, pos));
+                }
+                Inst::Assert(assertion, next) => {
+                    if assertion.holds_at(chars, pos) {
+                        self.stack.push(Frame::Explore(next, pos));
+                    }
+                }
+                Inst::Look(look, next) => {
+                    if haystack.looks[look as usize][pos] {
+                        self.stack.push(Frame::Explore(next, pos));
+                    }
+                }
+                Inst::Save(slot, next) => {
+                    let slot = slot as usize;
+                    self.stack.push(Frame::Restore(slot, self.slots[slot]));
+                    self.slots[slot] = Some(pos);
+                    self.stack.push(Frame::Explore(next, pos));
+                }
+                Inst::Backref(group, caseless, next) => {
+                    let group = group as usize;
+                    if let (Some(from), Some(to)) =
+                        (self.slots[2 * group], self.slots[2 * group + 1])
+                    {
+                        let matches = from <= to
+                            && pos + (to - from) <= chars.len()
+                            && chars[from..to]
+                                .iter()
+                                .zip(&chars[pos..])
+                                .all(|(&a, &b)| a == b || caseless && eq_ignoring_case(a, b));
+                        if matches {
+                            self.stack.push(Frame::Explore(next, pos + to - from));
+                        }
+                    }
+                }
+                ref inst => {
+                    if pos < chars.len() {
+                        if let Some(next) = inst.step(chars[pos]) {
+                            self.stack.push(Frame::Explore(next, pos + 1));
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Program {
+    /// Depth-first backtracking that never explores the same instruction at the same position
+    /// twice. A failed `(instruction, position)` pair will fail again, so the whole search takes
+    /// `O(instructions × input)` time, at the cost of one bit of memory per pair.
+    ///
+    /// `run_backtracking` is kept around to show what happens without the visited set.
+    pub fn run_bounded_backtracking(&self, s: &str) -> bool {
+        let haystack = Haystack::new(self, s);
+        let mut backtracker = Backtracker::new(self);
+        backtracker.reset(self, &haystack);
+        backtracker.search(self, &haystack, 0, true).is_some()
+    }
+
+    /// Searches for the leftmost match the way Perl and PCRE do: starting positions are tried
+    /// from left to right, and at each of them branches are explored depth-first in priority
+    /// order, so the first `Match` reached wins. Alternatives are tried in the order they are
+    /// written, and greedy loops try one more iteration before leaving.
+    ///
+    /// Returns byte offsets of the match. Like `run_bounded_backtracking`, visited
+    /// `(instruction, position)` pairs are not explored again: a pair that was already explored
+    /// did not lead to a match, so skipping it doesn't change which match is found first.
+    ///
+    /// Lookarounds are looked up in tables computed up front by `look_tables`, so they only
+    /// depend on the position and don't get in the way of skipping visited pairs. Backreferences
+    /// do, since whether they match depends on what their groups captured on the path taken, so
+    /// for programs with backreferences pairs are only skipped when those groups captured the
+    /// same text too. Loops that match an empty string still end, but the search can take
+    /// exponential time.
+    pub fn find_backtracking(&self, s: &str) -> Option<(usize, usize)> {
+        self.captures_backtracking(s)
+            .map(|groups| groups[0].unwrap())
+    }
+
+    /// Same search as `find_backtracking`, returning byte offsets of every group as well, with
+    /// the whole match as group 0. Groups that did not take part in the match are `None`.
+    pub fn captures_backtracking(&self, s: &str) -> Option<Vec<Option<(usize, usize)>>> {
+        self.captures_backtracking_at(s, 0)
+    }
+
+    /// Same as `captures_backtracking`, for a match starting at byte offset `start` or later.
+    /// The text before `start` is still there for assertions and lookbehinds to look at.
+    pub fn captures_backtracking_at(
+        &self,
+        s: &str,
+        start: usize,
+    ) -> Option<Vec<Option<(usize, usize)>>> {
+        Backtracker::new(self).captures(self, &Haystack::new(self, s), start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Token::Or;
+    use AST;
+
+    fn adversarial() -> AST {
+        // (x+)+y
+        let x_plus = || AST::concat(vec![AST::literal('x'), AST::star(AST::literal('x'))]);
+        AST::concat(vec![x_plus(), AST::star(x_plus()), AST::literal('y')])
+    }
+
+    #[test]
+    fn bounded_backtracking() {
+        let program = adversarial().into_program();
+
+        assert!(program.run_bounded_backtracking("xy"));
+        assert!(program.run_bounded_backtracking("xxxxy"));
+        assert!(!program.run_bounded_backtracking("y"));
+        assert!(!program.run_bounded_backtracking("xxxx"));
+    }
+
+    #[test]
+    fn leftmost_first() {
+        // a|ab, not using `AST::or` as it sorts the alternatives
+        let program = AST::new(
+            Or,
+            Some(vec![
+                AST::literal('a'),
+                AST::concat(vec![AST::literal('a'), AST::literal('b')]),
+            ]),
+        )
+        .into_program();
+
+        assert_eq!(program.find_backtracking("ab"), Some((0, 1)));
+        assert_eq!(program.find_backtracking("cab"), Some((1, 2)));
+        assert_eq!(program.find_backtracking("c"), None);
+    }
+
+    #[test]
+    fn alternation_order() {
+        // (a|ab)(c|bcd)
+        let program = AST::concat(vec![
+            AST::new(
+                Or,
+                Some(vec![
+                    AST::literal('a'),
+                    AST::concat(vec![AST::literal('a'), AST::literal('b')]),
+                ]),
+            ),
+            AST::new(
+                Or,
+                Some(vec![
+                    AST::literal('c'),
+                    AST::concat(vec![
+                        AST::literal('b'),
+                        AST::literal('c'),
+                        AST::literal('d'),
+                    ]),
+                ]),
+            ),
+        ])
+        .into_program();
+
+        assert_eq!(program.find_backtracking("abcd"), Some((0, 4)));
+        assert_eq!(program.find_backtracking("xabc"), Some((1, 4)));
+    }
+
+    #[test]
+    fn greedy() {
+        let program = AST::star(AST::literal('x')).into_program();
+
+        assert_eq!(program.find_backtracking("xxx"), Some((0, 3)));
+        assert_eq!(program.find_backtracking("yxx"), Some((0, 0)));
+    }
+
+    #[test]
+    fn byte_offsets() {
+        let program = AST::literal('b').into_program();
+
+        assert_eq!(program.find_backtracking("äb"), Some((2, 3)));
+    }
+
+    #[test]
+    fn bounded_backtracking_is_not_exponential() {
+        let program = adversarial().into_program();
+        let input: String = (0..1000).map(|_| 'x').collect();
+
+        assert!(!program.run_bounded_backtracking(&input));
+    }
+
+    #[test]
+    fn captures() {
+        let program = AST::parse("(a+)(b)?(c)").unwrap().into_program();
+
+        assert_eq!(program.captures_backtracking("xaäc"), None);
+        assert_eq!(
+            program.captures_backtracking("äaac"),
+            Some(vec![Some((2, 5)), Some((2, 4)), None, Some((4, 5))])
+        );
+    }
+
+    #[test]
+    fn backrefs() {
+        let program = AST::parse("(a|b)\\1").unwrap().into_program();
+
+        assert_eq!(program.find_backtracking("abba"), Some((1, 3)));
+        assert_eq!(program.find_backtracking("abab"), None);
+
+        let program = AST::parse("(?<x>a*)b\\k<x>$").unwrap().into_program();
+
+        assert_eq!(program.find_backtracking("aabaa"), Some((0, 5)));
+        assert_eq!(program.find_backtracking("aaba"), Some((1, 4)));
+        assert!(program.run_bounded_backtracking("aba"));
+        assert!(!program.run_bounded_backtracking("abaa"));
+
+        let program = AST::parse("(?i)(ab)\\1").unwrap().into_program();
+        assert_eq!(program.find_backtracking("xaBAb"), Some((1, 5)));
+        assert_eq!(program.find_backtracking("abac"), None);
+
+        let program = AST::parse("(?i:(a))\\1").unwrap().into_program();
+        assert_eq!(program.find_backtracking("aAA"), Some((1, 3)));
+    }
+
+    #[test]
+    fn backrefs_with_empty_loops() {
+        let program = AST::parse("(a)(?:b*)*\\1").unwrap().into_program();
+
+        assert_eq!(program.find_backtracking("ac"), None);
+        assert_eq!(program.find_backtracking("abba"), Some((0, 4)));
+
+        let program = AST::parse("(a)(?:)*x\\1").unwrap().into_program();
+
+        assert_eq!(program.find_backtracking("ab"), None);
+        assert_eq!(program.find_backtracking("axa"), Some((0, 3)));
+    }
+
+    #[test]
+    fn backrefs_to_unset_groups() {
+        let program = AST::parse("(?:(a)|b)\\1").unwrap().into_program();
+
+        assert_eq!(program.find_backtracking("baa"), Some((1, 3)));
+        assert_eq!(program.find_backtracking("bb"), None);
+    }
+}
@@ -0,0 +1,181 @@
+use program::{Inst, InstPtr};
+use Program;
+
+/// Bit-parallel simulation for programs with at most 64 `Char`, `Range` and `Match`
+/// instructions, in the spirit of Shift-And: the set of active instructions is a single `u64`.
+///
+/// One step intersects the active set with the mask of instructions accepting the character,
+/// then looks up the epsilon closures of all their successors in tables indexed by each byte of
+/// the intersection, eight lookups per character at most.
+#[derive(Debug, Clone)]
+pub struct BitParallel {
+    start: u64,
+    accept: u64,
+    /// Masks of instructions accepting each ASCII character.
+    ascii: Vec<u64>,
+    /// Consuming instructions with their bits, to compute masks for the rest of Unicode.
+    consumers: Vec<(u64, Inst)>,
+    /// `follow[k][b]` is the union of closures of successors of the bits set in `b`, which is the
+    /// `k`-th byte of the mask.
+    follow: Vec<[u64; 256]>,
+}
+
+impl BitParallel {
+    pub const MAX_STATES: usize = 64;
+
+    /// Returns `None` if the program has too many states to fit in a `u64`, or if it has
+    /// assertions, lookarounds or backreferences, which would make closures depend on the input.
+    pub fn new(program: &Program) -> Option<BitParallel> {
+        if program
+            .insts
+            .iter()
+            .any(|inst| matches!(*inst, Inst::Assert(..) | Inst::Look(..) | Inst::Backref(..)))
+        {
+            return None;
+        }
+
+        let mut bits = vec![None; program.insts.len()];
+        let mut states = Vec::new();
+        for (pc, inst) in program.insts.iter().enumerate() {
+            match *inst {
+                Inst::Match | Inst::Char(..) | Inst::Range(..) => {
+                    bits[pc] = Some(states.len());
+                    states.push(*inst);
+                }
+                _ => {}
+            }
+        }
+        if states.len() > BitParallel::MAX_STATES {
+            return None;
+        }
+
+        let closure = |pc: InstPtr| -> u64 {
+            let mut result = 0;
+            let mut seen = vec![false; program.insts.len()];
+            let mut stack = vec![pc];
+            while let Some(pc) = stack.pop() {
+                if seen[pc as usize] {
+                    continue;
+                }
+                seen[pc as usize] = true;
+
+                match program.insts[pc as usize] {
+                    Inst::Jump(next) | Inst::Save(_, next) => stack.push(next),
+                    Inst::Split(x, y) => {
+                        stack.push(x);
+                        stack.push(y);
+                    }
+                    _ => {}
+                }
+                if let Some(bit) = bits[pc as usize] {
+                    result |= 1 << bit;
+                }
+            }
+            result
+        };
+
+        let mut accept = 0;
+        let mut consumers = Vec::new();
+        let mut successors = Vec::new();
+        for (bit, inst) in states.iter().enumerate() {
+            match *inst {
+                Inst::Char(_, next) | Inst::Range(_, _, next) => {
+                    consumers.push((1 << bit, *inst));
+                    successors.push(closure(next));
+                }
+                _ => {
+                    accept |= 1 << bit;
+                    successors.push(0);
+                }
+            }
+        }
+
+        let mut follow = Vec::new();
+        for chunk in successors.chunks(8) {
+            let mut table = [0; 256];
+            for b in 1..256 {
+                table[b] =
+                    table[b & (b - 1)] | chunk.get(b.trailing_zeros() as usize).unwrap_or(&0);
+            }
+            follow.push(table);
+        }
+
+        let mut result = BitParallel {
+            start: closure(program.start),
+            accept,
+            ascii: Vec::new(),
+            consumers,
+            follow,
+        };
+        result.ascii = (0..128u8).map(|c| result.mask(c as char)).collect();
+
+        Some(result)
+    }
+
+    fn mask(&self, c: char) -> u64 {
+        if (c as u32) < 128 && !self.ascii.is_empty() {
+            return self.ascii[c as usize];
+        }
+
+        self.consumers
+            .iter()
+            .filter(|&&(_, inst)| inst.step(c).is_some())
+            .fold(0, |acc, &(bit, _)| acc | bit)
+    }
+
+    pub fn run(&self, s: &str) -> bool {
+        let mut state = self.start;
+        for c in s.chars() {
+            let active = state & self.mask(c);
+            if active == 0 {
+                return false;
+            }
+
+            state = 0;
+            for (k, table) in self.follow.iter().enumerate() {
+                state |= table[((active >> (8 * k)) & 0xff) as usize];
+            }
+        }
+
+        state & self.accept != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AST;
+
+    #[test]
+    fn bit_parallel() {
+        // (a|b)*abb
+        let ast = AST::concat(vec![
+            AST::star(AST::or(vec![AST::literal('a'), AST::literal('b')])),
+            AST::literal('a'),
+            AST::literal('b'),
+            AST::literal('b'),
+        ]);
+        let bits = BitParallel::new(&ast.into_program()).unwrap();
+
+        assert!(bits.run("abb"));
+        assert!(bits.run("bababb"));
+        assert!(!bits.run(""));
+        assert!(!bits.run("abbä"));
+    }
+
+    #[test]
+    fn too_many_states() {
+        let ast = AST::concat((0..100).map(|_| AST::literal('a')).collect());
+
+        assert!(BitParallel::new(&ast.into_program()).is_none());
+    }
+
+    #[test]
+    fn assertions() {
+        let ast = AST::parse("^a").unwrap();
+        assert!(BitParallel::new(&ast.into_program()).is_none());
+
+        let ast = AST::parse("(?=a)a").unwrap();
+        assert!(BitParallel::new(&ast.into_program()).is_none());
+    }
+}
@@ -0,0 +1,242 @@
+use std::char;
+use std::cmp::Ordering;
+
+/// A set of characters, stored as sorted, non-overlapping and non-adjacent inclusive ranges.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CharClass {
+    ranges: Vec<(char, char)>,
+}
+
+const CASED_MAX: char = '\u{1FFFF}';
+
+/// Lower and upper case of `c` when they are single characters, and their own other case, which
+/// catches characters like the Kelvin sign whose lower case is a plain `k`.
+fn simple_cases(c: char) -> impl Iterator<Item = char> {
+    fn single<I: Iterator<Item = char>>(mut chars: I) -> Option<char> {
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    }
+
+    let lower = single(c.to_lowercase());
+    let upper = single(c.to_uppercase());
+    let upper_of_lower = lower.and_then(|l| single(l.to_uppercase()));
+    let lower_of_upper = upper.and_then(|u| single(u.to_lowercase()));
+    vec![lower, upper, upper_of_lower, lower_of_upper]
+        .into_iter()
+        .flatten()
+}
+
+/// Checks if `a` matches `b` ignoring case, the same way `(?i)` makes a literal `a` match.
+pub fn eq_ignoring_case(a: char, b: char) -> bool {
+    a == b || simple_cases(a).any(|c| c == b)
+}
+
+/// The character right after `c`, skipping surrogates.
+pub fn next_char(c: char) -> Option<char> {
+    match c {
+        '\u{D7FF}' => Some('\u{E000}'),
+        char::MAX => None,
+        _ => char::from_u32(c as u32 + 1),
+    }
+}
+
+/// The character right before `c`, skipping surrogates.
+pub fn previous_char(c: char) -> Option<char> {
+    match c {
+        '\u{E000}' => Some('\u{D7FF}'),
+        '\0' => None,
+        _ => char::from_u32(c as u32 - 1),
+    }
+}
+
+impl CharClass {
+    pub fn new(mut ranges: Vec<(char, char)>) -> CharClass {
+        ranges.retain(|&(lo, hi)| lo <= hi);
+        ranges.sort();
+
+        let mut result: Vec<(char, char)> = Vec::new();
+        for (lo, hi) in ranges {
+            if let Some(last) = result.last_mut() {
+                if next_char(last.1).is_none_or(|next| lo <= next) {
+                    last.1 = last.1.max(hi);
+                    continue;
+                }
+            }
+            result.push((lo, hi));
+        }
+
+        CharClass { ranges: result }
+    }
+
+    pub fn any() -> CharClass {
+        CharClass::new(vec![('\0', char::MAX)])
+    }
+
+    pub fn ranges(&self) -> &[(char, char)] {
+        &self.ranges
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn contains(&self, c: char) -> bool {
+        self.ranges
+            .binary_search_by(|&(lo, hi)| {
+                if hi < c {
+                    Ordering::Less
+                } else if lo > c {
+                    Ordering::Greater
+                } else {
+                    Ordering::Equal
+                }
+            })
+            .is_ok()
+    }
+
+    pub fn negate(&self) -> CharClass {
+        let mut result = Vec::new();
+        let mut lo = Some('\0');
+        for &(start, end) in &self.ranges {
+            if let (Some(l), Some(before)) = (lo, previous_char(start)) {
+                result.push((l, before));
+            }
+            lo = next_char(end);
+        }
+        if let Some(l) = lo {
+            result.push((l, char::MAX));
+        }
+
+        CharClass::new(result)
+    }
+
+    /// Adds the other cases of every character, following simple (one character to one
+    /// character) case mappings. Only mappings of the characters in the class are followed, so
+    /// the Kelvin sign gets `k` and `K` but `k` doesn't get the Kelvin sign. No character above
+    /// U+1FFFF has a case, so larger ones are not even looked at.
+    pub fn fold_case(&self) -> CharClass {
+        let mut ranges = self.ranges.clone();
+        for &(lo, hi) in &self.ranges {
+            if lo > CASED_MAX {
+                break;
+            }
+            for c in lo..=hi.min(CASED_MAX) {
+                ranges.extend(simple_cases(c).map(|other| (other, other)));
+            }
+        }
+
+        CharClass::new(ranges)
+    }
+
+    pub fn union(&self, other: &CharClass) -> CharClass {
+        let mut ranges = self.ranges.clone();
+        ranges.extend(other.ranges.iter().cloned());
+        CharClass::new(ranges)
+    }
+}
+
+/// Splits all characters into intervals, so that every one of `ranges` is a union of whole
+/// intervals. Returns the first character of every interval, in order, starting with `'\0'`.
+///
+/// Characters of one interval can't be told apart by anything that only looks at `ranges`, so
+/// automata need a single transition per interval instead of one per character.
+pub fn partition<I: IntoIterator<Item = (char, char)>>(ranges: I) -> Vec<char> {
+    let mut boundaries = vec!['\0'];
+    for (lo, hi) in ranges {
+        boundaries.push(lo);
+        if let Some(next) = next_char(hi) {
+            boundaries.push(next);
+        }
+    }
+    boundaries.sort();
+    boundaries.dedup();
+
+    boundaries
+}
+
+/// Finds the first character of the interval of `partition` containing `c`.
+pub fn representative(partition: &[char], c: char) -> char {
+    match partition.binary_search(&c) {
+        Ok(_) => c,
+        Err(i) => partition[i - 1],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical() {
+        let class = CharClass::new(vec![('x', 'z'), ('a', 'c'), ('d', 'f'), ('b', 'b')]);
+
+        assert_eq!(class.ranges(), &[('a', 'f'), ('x', 'z')]);
+    }
+
+    #[test]
+    fn contains() {
+        let class = CharClass::new(vec![('a', 'c'), ('x', 'z')]);
+
+        assert!(class.contains('a'));
+        assert!(class.contains('y'));
+        assert!(!class.contains('d'));
+        assert!(!class.contains('A'));
+    }
+
+    #[test]
+    fn word() {
+        let word = CharClass::word();
+
+        assert_eq!(
+            &word.ranges()[..4],
+            &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')]
+        );
+        assert!(word.contains('ß'));
+        assert!(!word.contains(' '));
+    }
+
+    #[test]
+    fn fold_case() {
+        let class = CharClass::new(vec![('a', 'c'), ('x', 'x'), ('ä', 'ä'), ('1', '1')]);
+
+        assert_eq!(
+            class.fold_case().ranges(),
+            &[
+                ('1', '1'),
+                ('A', 'C'),
+                ('X', 'X'),
+                ('a', 'c'),
+                ('x', 'x'),
+                ('Ä', 'Ä'),
+                ('ä', 'ä')
+            ]
+        );
+        assert!(CharClass::new(vec![('\u{212A}', '\u{212A}')])
+            .fold_case()
+            .contains('K'));
+        assert!(!CharClass::new(vec![('ß', 'ß')]).fold_case().contains('S'));
+        assert_eq!(CharClass::any().fold_case(), CharClass::any());
+    }
+
+    #[test]
+    fn negate() {
+        let class = CharClass::new(vec![('\0', '9'), ('b', 'b')]);
+
+        assert_eq!(class.negate().ranges(), &[(':', 'a'), ('c', char::MAX)]);
+        assert_eq!(class.negate().negate(), class);
+        assert!(CharClass::any().negate().is_empty());
+    }
+
+    #[test]
+    fn partition_and_representative() {
+        let partition = partition(vec![('a', 'z'), ('k', 'k')]);
+
+        assert_eq!(partition, vec!['\0', 'a', 'k', 'l', '{']);
+        assert_eq!(representative(&partition, 'c'), 'a');
+        assert_eq!(representative(&partition, 'k'), 'k');
+        assert_eq!(representative(&partition, 'A'), '\0');
+        assert_eq!(representative(&partition, 'ä'), '{');
+    }
+}
@@ -0,0 +1,558 @@
+use ast::Token::*;
+use nfa::Transition::{self, Character};
+use std::collections::{BTreeMap, BTreeSet};
+use {Look, Program, State, AST, NFA};
+
+use errors::*;
+
+/// The algorithm used to turn an `AST` into an `NFA`.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Construction {
+    /// McNaughton–Yamada–Thompson construction: a couple of states per node, glued together with
+    /// epsilon transitions.
+    Thompson,
+    /// Glushkov (position automaton) construction: one state per literal plus an initial state,
+    /// and no epsilon transitions at all.
+    Glushkov,
+}
+
+#[derive(Debug, Clone)]
+pub struct Compiler {
+    construction: Construction,
+}
+
+impl Default for Compiler {
+    fn default() -> Compiler {
+        Compiler {
+            construction: Construction::Thompson,
+        }
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        Compiler::default()
+    }
+
+    pub fn construction(mut self, construction: Construction) -> Compiler {
+        self.construction = construction;
+        self
+    }
+
+    /// Fails for backreferences, which automata can't follow, see `compile_program`.
+    pub fn compile(&self, ast: AST) -> Result<NFA> {
+        let nfa = self.compile_any(&ast);
+        if nfa.has_backrefs() {
+            bail!(ErrorKind::UnsupportedFeature("backreference".to_string()));
+        }
+
+        Ok(nfa)
+    }
+
+    /// Compiles to a program, which can have backreferences: programs with them are always
+    /// searched by backtracking.
+    pub fn compile_program(&self, ast: AST) -> Program {
+        self.compile_any(&ast).to_program()
+    }
+
+    fn compile_any(&self, ast: &AST) -> NFA {
+        match self.construction {
+            Construction::Thompson => Thompson::default().compile(ast),
+            Construction::Glushkov => Glushkov::default().compile(ast),
+        }
+    }
+}
+
+/// All states are allocated from a single counter, and every fragment is built starting from a
+/// state handed to it by its parent, so no renumbering or merging of fragments is ever needed and
+/// construction is linear in the size of the tree.
+#[derive(Default)]
+struct Thompson {
+    states: State,
+    transitions: BTreeMap<(State, Transition), BTreeSet<State>>,
+    looks: Vec<(Look, NFA)>,
+}
+
+impl Thompson {
+    fn compile(mut self, ast: &AST) -> NFA {
+        let start = self.add_state();
+        let accept = self.fragment(ast, start);
+
+        NFA::new(start, btreeset!(accept), self.transitions).with_looks(self.looks)
+    }
+
+    fn add_state(&mut self) -> State {
+        self.states += 1;
+        self.states - 1
+    }
+
+    fn add_transition(&mut self, from: State, transition: Transition, to: State) {
+        self.transitions
+            .entry((from, transition))
+            .or_insert(btreeset!())
+            .insert(to);
+    }
+
+    /// Builds the fragment for `ast` entered through `start`, and returns its accepting state.
+    fn fragment(&mut self, ast: &AST, start: State) -> State {
+        match *ast.token() {
+            Literal(c) => {
+                let accept = self.add_state();
+                self.add_transition(start, Character(c), accept);
+                accept
+            }
+            Class(ref class) => {
+                let accept = self.add_state();
+                for &(lo, hi) in class.ranges() {
+                    self.add_transition(start, Transition::Range(lo, hi), accept);
+                }
+                accept
+            }
+            Assertion(assertion) => {
+                let accept = self.add_state();
+                self.add_transition(start, Transition::Assert(assertion), accept);
+                accept
+            }
+            Look(look) => {
+                let nfa = Thompson::default().compile(&ast.children()[0]);
+                self.looks.push((look, nfa));
+
+                let accept = self.add_state();
+                let index = self.looks.len() as u32 - 1;
+                self.add_transition(start, Transition::Look(index), accept);
+                accept
+            }
+            Group(index, _) => {
+                let child_start = self.add_state();
+                self.add_transition(start, Transition::Save(2 * index), child_start);
+                let child_accept = self.fragment(&ast.children()[0], child_start);
+
+                let accept = self.add_state();
+                self.add_transition(child_accept, Transition::Save(2 * index + 1), accept);
+                accept
+            }
+            Backref(index, caseless) => {
+                let accept = self.add_state();
+                self.add_transition(start, Transition::Backref(index, caseless), accept);
+                accept
+            }
+            CaseInsensitive => self.fragment(&ast.children()[0].fold_case(), start),
+            Epsilon => start,
+            Empty => self.add_state(),
+            Concat => ast
+                .children()
+                .iter()
+                .fold(start, |accept, child| self.fragment(child, accept)),
+            Or => {
+                let mut accepts = Vec::new();
+                for child in ast.children() {
+                    let child_start = self.add_state();
+                    self.add_transition(start, Transition::Epsilon, child_start);
+                    accepts.push(self.fragment(child, child_start));
+                }
+
+                let accept = self.add_state();
+                for a in accepts {
+                    self.add_transition(a, Transition::Epsilon, accept);
+                }
+                accept
+            }
+            Star => {
+                let child_start = self.add_state();
+                let child_accept = self.fragment(&ast.children()[0], child_start);
+                let accept = self.add_state();
+
+                self.add_transition(start, Transition::Epsilon, child_start);
+                self.add_transition(start, Transition::Epsilon, accept);
+                self.add_transition(child_accept, Transition::Epsilon, child_start);
+                self.add_transition(child_accept, Transition::Epsilon, accept);
+                accept
+            }
+            Plus => {
+                let child_start = self.add_state();
+                let child_accept = self.fragment(&ast.children()[0], child_start);
+                let accept = self.add_state();
+
+                self.add_transition(start, Transition::Epsilon, child_start);
+                self.add_transition(child_accept, Transition::Epsilon, child_start);
+                self.add_transition(child_accept, Transition::Epsilon, accept);
+                accept
+            }
+            LazyPlus => {
+                let accept = self.add_state();
+                let child_start = self.add_state();
+                let child_accept = self.fragment(&ast.children()[0], child_start);
+
+                self.add_transition(start, Transition::Epsilon, child_start);
+                self.add_transition(child_accept, Transition::Epsilon, accept);
+                self.add_transition(child_accept, Transition::Epsilon, child_start);
+                accept
+            }
+            LazyStar => {
+                // Priority of epsilon transitions follows state ids, so the exit has to be
+                // allocated before the loop to be preferred.
+                let accept = self.add_state();
+                let child_start = self.add_state();
+                let child_accept = self.fragment(&ast.children()[0], child_start);
+
+                self.add_transition(start, Transition::Epsilon, accept);
+                self.add_transition(start, Transition::Epsilon, child_start);
+                self.add_transition(child_accept, Transition::Epsilon, accept);
+                self.add_transition(child_accept, Transition::Epsilon, child_start);
+                accept
+            }
+        }
+    }
+}
+
+/// Every literal or class of the tree becomes a position (a state numbered from 1), and state 0
+/// is the initial state. There is a transition `p -> q` labeled with the literal of `q` whenever
+/// `q` can follow `p` in some word of the language, so the resulting NFA has exactly `n + 1`
+/// states for `n` literals. Only the language matters here, so lazy stars are the same as greedy
+/// ones.
+///
+/// Assertions and lookarounds are positions too, whose incoming transitions don't consume
+/// anything. Patterns of lookarounds are compiled separately. So are both ends of groups and
+/// backreferences, which is enough to keep captures right as long as there is only one way to
+/// match.
+#[derive(Default)]
+struct Glushkov {
+    labels: Vec<Vec<Transition>>,
+    follow: BTreeMap<State, BTreeSet<State>>,
+    looks: Vec<(Look, NFA)>,
+}
+
+/// Nullability, first positions and last positions of a subtree.
+struct Positions {
+    nullable: bool,
+    first: BTreeSet<State>,
+    last: BTreeSet<State>,
+}
+
+impl Glushkov {
+    fn compile(mut self, ast: &AST) -> NFA {
+        let root = self.positions(ast);
+
+        let mut transitions = BTreeMap::new();
+        let targets = Some((0, &root.first))
+            .into_iter()
+            .chain(self.follow.iter().map(|(p, f)| (*p, f)));
+        for (p, follow) in targets {
+            for q in follow {
+                for t in self.label(*q) {
+                    transitions.entry((p, *t)).or_insert(btreeset!()).insert(*q);
+                }
+            }
+        }
+
+        let mut accept = root.last;
+        if root.nullable {
+            accept.insert(0);
+        }
+
+        NFA::new(0, accept, transitions).with_looks(self.looks)
+    }
+
+    fn label(&self, position: State) -> &[Transition] {
+        &self.labels[position as usize - 1]
+    }
+
+    fn add_position(&mut self, label: Vec<Transition>) -> Positions {
+        self.labels.push(label);
+        let position = self.labels.len() as State;
+        Positions {
+            nullable: false,
+            first: btreeset!(position),
+            last: btreeset!(position),
+        }
+    }
+
+    fn add_follow(&mut self, from: &BTreeSet<State>, to: &BTreeSet<State>) {
+        for p in from {
+            self.follow.entry(*p).or_insert(btreeset!()).extend(to);
+        }
+    }
+
+    /// Positions of a concatenation of parts.
+    fn sequence(&mut self, parts: Vec<Positions>) -> Positions {
+        let mut result = Positions {
+            nullable: true,
+            first: BTreeSet::new(),
+            last: BTreeSet::new(),
+        };
+        for mut part in parts {
+            self.add_follow(&result.last, &part.first);
+
+            if result.nullable {
+                result.first.extend(part.first);
+            }
+            if part.nullable {
+                part.last.extend(result.last);
+            }
+            result.last = part.last;
+            result.nullable &= part.nullable;
+        }
+        result
+    }
+
+    fn positions(&mut self, ast: &AST) -> Positions {
+        match *ast.token() {
+            Literal(c) => self.add_position(vec![Character(c)]),
+            Class(ref class) => self.add_position(
+                class
+                    .ranges()
+                    .iter()
+                    .map(|&(lo, hi)| Transition::Range(lo, hi))
+                    .collect(),
+            ),
+            Assertion(assertion) => self.add_position(vec![Transition::Assert(assertion)]),
+            Look(look) => {
+                let nfa = Glushkov::default().compile(&ast.children()[0]);
+                self.looks.push((look, nfa));
+
+                let index = self.looks.len() as u32 - 1;
+                self.add_position(vec![Transition::Look(index)])
+            }
+            Group(index, _) => {
+                let open = self.add_position(vec![Transition::Save(2 * index)]);
+                let child = self.positions(&ast.children()[0]);
+                let close = self.add_position(vec![Transition::Save(2 * index + 1)]);
+                self.sequence(vec![open, child, close])
+            }
+            Backref(index, caseless) => {
+                self.add_position(vec![Transition::Backref(index, caseless)])
+            }
+            CaseInsensitive => self.positions(&ast.children()[0].fold_case()),
+            Epsilon | Empty => Positions {
+                nullable: *ast.token() == Epsilon,
+                first: BTreeSet::new(),
+                last: BTreeSet::new(),
+            },
+            Or => {
+                let mut result = Positions {
+                    nullable: false,
+                    first: BTreeSet::new(),
+                    last: BTreeSet::new(),
+                };
+                for child in ast.children() {
+                    let child = self.positions(child);
+                    result.nullable |= child.nullable;
+                    result.first.extend(child.first);
+                    result.last.extend(child.last);
+                }
+                result
+            }
+            Concat => {
+                let parts = ast.children().iter().map(|x| self.positions(x)).collect();
+                self.sequence(parts)
+            }
+            Star | LazyStar | Plus | LazyPlus => {
+                let child = self.positions(&ast.children()[0]);
+                self.add_follow(&child.last, &child.first);
+                Positions {
+                    nullable: child.nullable || *ast.token() == Star || *ast.token() == LazyStar,
+                    first: child.first,
+                    last: child.last,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Automaton;
+
+    #[test]
+    fn thompson_literal() {
+        assert_eq!(
+            Compiler::new().compile(AST::literal('a')).unwrap(),
+            NFA::new(
+                0,
+                btreeset!(1),
+                btreemap!((0, Character('a')) => btreeset!(1)),
+            )
+        );
+    }
+
+    #[test]
+    fn thompson_concat() {
+        assert_eq!(
+            Compiler::new()
+                .compile(AST::concat(vec![AST::literal('a'), AST::literal('b')]))
+                .unwrap(),
+            NFA::new(
+                0,
+                btreeset!(2),
+                btreemap!(
+                    (0, Character('a')) => btreeset!(1),
+                    (1, Character('b')) => btreeset!(2),
+                ),
+            )
+        );
+    }
+
+    #[test]
+    fn thompson_or() {
+        assert_eq!(
+            Compiler::new()
+                .compile(AST::or(vec![AST::literal('a'), AST::literal('b')]))
+                .unwrap(),
+            NFA::new(
+                0,
+                btreeset!(5),
+                btreemap!(
+                    (0, Transition::Epsilon) => btreeset!(1, 3),
+                    (1, Character('a')) => btreeset!(2),
+                    (2, Transition::Epsilon) => btreeset!(5),
+                    (3, Character('b')) => btreeset!(4),
+                    (4, Transition::Epsilon) => btreeset!(5),
+                ),
+            )
+        );
+    }
+
+    #[test]
+    fn thompson_star() {
+        assert_eq!(
+            Compiler::new()
+                .compile(AST::star(AST::literal('a')))
+                .unwrap(),
+            NFA::new(
+                0,
+                btreeset!(3),
+                btreemap!(
+                    (0, Transition::Epsilon) => btreeset!(1, 3),
+                    (1, Character('a')) => btreeset!(2),
+                    (2, Transition::Epsilon) => btreeset!(1, 3),
+                ),
+            )
+        );
+    }
+
+    #[test]
+    fn thompson_is_linear() {
+        let ast = AST::concat((0..10_000).map(|_| AST::literal('a')).collect());
+
+        assert_eq!(Compiler::new().compile(ast).unwrap().states().len(), 10_001);
+    }
+
+    #[test]
+    fn glushkov_literal() {
+        let nfa = Compiler::new()
+            .construction(Construction::Glushkov)
+            .compile(AST::literal('a'))
+            .unwrap();
+
+        assert_eq!(
+            nfa,
+            NFA::new(
+                0,
+                btreeset!(1),
+                btreemap!((0, Character('a')) => btreeset!(1)),
+            )
+        );
+    }
+
+    #[test]
+    fn glushkov_star() {
+        let nfa = Compiler::new()
+            .construction(Construction::Glushkov)
+            .compile(AST::star(AST::literal('a')))
+            .unwrap();
+
+        assert_eq!(
+            nfa,
+            NFA::new(
+                0,
+                btreeset!(0, 1),
+                btreemap!(
+                    (0, Character('a')) => btreeset!(1),
+                    (1, Character('a')) => btreeset!(1),
+                ),
+            )
+        );
+    }
+
+    #[test]
+    fn glushkov_states() {
+        let nfa = Compiler::new()
+            .construction(Construction::Glushkov)
+            .compile(AST::parse("(?:a|b)*abb").unwrap())
+            .unwrap();
+
+        assert_eq!(nfa.states(), btreeset!(0, 1, 2, 3, 4, 5));
+    }
+
+    #[test]
+    fn glushkov_matches_thompson() {
+        let ast = AST::parse("(?:a|b)*abb").unwrap();
+        let thompson = Compiler::new().compile(ast.clone()).unwrap();
+        let glushkov = Compiler::new()
+            .construction(Construction::Glushkov)
+            .compile(ast)
+            .unwrap();
+
+        for s in &["", "abb", "aabb", "babb", "ab", "abba", "c"] {
+            assert_eq!(thompson.run(s), glushkov.run(s));
+            assert_eq!(
+                thompson.to_dfa().unwrap().run(s),
+                glushkov.to_dfa().unwrap().run(s)
+            );
+        }
+    }
+
+    #[test]
+    fn plus() {
+        let ast = AST::parse("(?:ab?)+c|(?:x*)+?y").unwrap();
+        let thompson = Compiler::new().compile(ast.clone()).unwrap();
+        let glushkov = Compiler::new()
+            .construction(Construction::Glushkov)
+            .compile(ast.clone())
+            .unwrap();
+
+        for s in &["", "c", "ac", "abaac", "abbc", "y", "xxy", "x"] {
+            let expected = ast.run(s).unwrap();
+            assert_eq!(thompson.run(s), expected, "{}", s);
+            assert_eq!(glushkov.run(s), expected, "{}", s);
+        }
+        assert!(thompson.run("abac") && !thompson.run("c"));
+    }
+
+    #[test]
+    fn glushkov_zero_width() {
+        let ast = AST::parse("(^|x)(?<!y)a*\\b(?=b|$)").unwrap();
+        let thompson = Compiler::new().compile(ast.clone()).unwrap();
+        let glushkov = Compiler::new()
+            .construction(Construction::Glushkov)
+            .compile(ast)
+            .unwrap();
+
+        for s in &["", "a", "xa", "xaa", "ya", "xab", "x", "b"] {
+            assert_eq!(thompson.run(s), glushkov.run(s), "{}", s);
+        }
+        assert!(glushkov.run("xaa"));
+    }
+
+    #[test]
+    fn backrefs() {
+        let ast = AST::parse("(a|b)\\1").unwrap();
+        for &construction in &[Construction::Thompson, Construction::Glushkov] {
+            let compiler = Compiler::new().construction(construction);
+            match compiler.compile(ast.clone()) {
+                Err(Error(ErrorKind::UnsupportedFeature(_), _)) => {}
+                _ => panic!("automata can't follow backreferences"),
+            }
+
+            let program = compiler.compile_program(ast.clone());
+            assert!(program.run("aa") && !program.run("ab"));
+            assert!(program.run_backtracking("bb"));
+            assert_eq!(program.find("abba"), Some((1, 3)));
+            assert_eq!(
+                program.captures("abba"),
+                Some(vec![Some((1, 3)), Some((1, 2))])
+            );
+        }
+    }
+}
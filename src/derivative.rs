@@ -0,0 +1,249 @@
+use ast::Token::*;
+use class::partition;
+use std::collections::btree_map::Entry::Vacant;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use {AST, DFA};
+
+use errors::*;
+
+/// Brzozowski derivatives work directly on the syntax tree: the derivative of `r` by `c` is a
+/// regex matching every `s` such that `r` matches `cs`. Matching is then repeated derivation
+/// followed by a nullability check, and the set of distinct derivatives of a regex forms a DFA.
+///
+/// Derivatives are built with the smart constructors from `ast`, which keeps the number of
+/// distinct (normalized) derivatives finite.
+///
+/// Assertions and lookarounds depend on the characters around them, and backreferences on
+/// earlier input, none of which derivatives keep track of, so `run` and
+/// `to_dfa_by_derivatives` reject them. Groups are dropped by normalization, and
+/// case-insensitive subtrees are folded.
+impl AST {
+    /// Checks if the language of this tree contains an empty string.
+    pub fn nullable(&self) -> bool {
+        match *self.token() {
+            Epsilon | Star | LazyStar => true,
+            Literal(_) | Class(_) | Assertion(_) | Look(_) | Backref(..) | Empty => false,
+            Group(..) | CaseInsensitive | Plus | LazyPlus => self.children()[0].nullable(),
+            Concat => self.children().iter().all(|x| x.nullable()),
+            Or => self.children().iter().any(|x| x.nullable()),
+        }
+    }
+
+    pub fn derivative(&self, c: char) -> AST {
+        match *self.token() {
+            Epsilon | Assertion(_) | Look(_) | Backref(..) | Empty => AST::empty(),
+            Group(..) => self.children()[0].derivative(c),
+            CaseInsensitive => self.children()[0].fold_case().derivative(c),
+            Literal(l) => {
+                if l == c {
+                    AST::epsilon()
+                } else {
+                    AST::empty()
+                }
+            }
+            Class(ref class) => {
+                if class.contains(c) {
+                    AST::epsilon()
+                } else {
+                    AST::empty()
+                }
+            }
+            Or => AST::or(self.children().iter().map(|x| x.derivative(c)).collect()),
+            Star | LazyStar => AST::concat(vec![self.children()[0].derivative(c), self.clone()]),
+            Plus | LazyPlus => {
+                let child = &self.children()[0];
+                AST::concat(vec![child.derivative(c), AST::star(child.clone())])
+            }
+            Concat => {
+                let (first, rest) = self.children().split_first().unwrap();
+                let rest = AST::concat(rest.to_vec());
+
+                let head = AST::concat(vec![first.derivative(c), rest.clone()]);
+                if first.nullable() {
+                    AST::or(vec![head, rest.derivative(c)])
+                } else {
+                    head
+                }
+            }
+        }
+    }
+
+    /// Matches the whole string by deriving the tree by each of its characters in turn.
+    pub fn run(&self, s: &str) -> Result<bool> {
+        self.check_supported()?;
+
+        let mut current = self.normalize();
+        for c in s.chars() {
+            current = current.derivative(c);
+            if let Empty = *current.token() {
+                return Ok(false);
+            }
+        }
+
+        Ok(current.nullable())
+    }
+
+    fn ranges(&self) -> Vec<(char, char)> {
+        let mut result = Vec::new();
+        match *self.token() {
+            Literal(c) => result.push((c, c)),
+            Class(ref class) => result.extend(class.ranges()),
+            _ => {}
+        }
+        for child in self.children() {
+            result.extend(child.ranges());
+        }
+
+        result
+    }
+
+    /// Name of the first feature derivatives can't handle, if any.
+    fn unsupported(&self) -> Option<&'static str> {
+        match *self.token() {
+            Assertion(_) => Some("assertion"),
+            Look(_) => Some("lookaround"),
+            Backref(..) => Some("backreference"),
+            _ => self
+                .children()
+                .iter()
+                .filter_map(|x| x.unsupported())
+                .nth(0),
+        }
+    }
+
+    fn check_supported(&self) -> Result<()> {
+        match self.unsupported() {
+            Some(feature) => bail!(ErrorKind::UnsupportedFeature(feature.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Builds a DFA whose states are the normalized derivatives of this tree. Derivatives that
+    /// are `Empty` are dead states, so they are left out of the transition table altogether.
+    ///
+    /// Only one character from each interval of the alphabet partition needs to be tried, since
+    /// all characters of an interval give the same derivative.
+    pub fn to_dfa_by_derivatives(&self) -> Result<DFA> {
+        self.check_supported()?;
+
+        let mut states = BTreeMap::new();
+        let mut accept = BTreeSet::new();
+        let mut transitions = BTreeMap::new();
+        let mut queue = VecDeque::new();
+
+        let init_state = self.normalize();
+        let partition = partition(init_state.ranges());
+        if init_state.nullable() {
+            accept.insert(0);
+        }
+        states.insert(init_state.clone(), 0);
+        queue.push_back((0, init_state));
+
+        while let Some((cur_id, cur_state)) = queue.pop_front() {
+            for a in &partition {
+                let new_state = cur_state.derivative(*a);
+                if let Empty = *new_state.token() {
+                    continue;
+                }
+
+                let next_id = states.len() as i32;
+                if let Vacant(entry) = states.entry(new_state.clone()) {
+                    if new_state.nullable() {
+                        accept.insert(next_id);
+                    }
+                    entry.insert(next_id);
+                    queue.push_back((next_id, new_state.clone()));
+                }
+
+                transitions.insert((cur_id, *a), states[&new_state]);
+            }
+        }
+
+        Ok(DFA::new(0, accept, transitions).with_partition(partition))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Automaton;
+
+    #[test]
+    fn nullable() {
+        assert!(AST::epsilon().nullable());
+        assert!(AST::star(AST::literal('a')).nullable());
+        assert!(!AST::literal('a').nullable());
+        assert!(!AST::empty().nullable());
+        assert!(!AST::parse("(?:a|b)*abb").unwrap().nullable());
+    }
+
+    #[test]
+    fn derivative() {
+        let ast = AST::concat(vec![AST::literal('a'), AST::literal('b')]);
+
+        assert_eq!(ast.derivative('a'), AST::literal('b'));
+        assert_eq!(ast.derivative('b'), AST::empty());
+        assert_eq!(ast.derivative('a').derivative('b'), AST::epsilon());
+    }
+
+    #[test]
+    fn run() {
+        let ast = AST::parse("(?:a|b)*abb").unwrap();
+
+        assert!(ast.run("abb").unwrap());
+        assert!(ast.run("babababb").unwrap());
+        assert!(!ast.run("").unwrap());
+        assert!(!ast.run("abba").unwrap());
+        assert!(!ast.run("abc").unwrap());
+    }
+
+    #[test]
+    fn dfa() {
+        let dfa = AST::parse("(?:a|b)*abb")
+            .unwrap()
+            .to_dfa_by_derivatives()
+            .unwrap();
+
+        assert!(dfa.run("abb"));
+        assert!(dfa.run("aababb"));
+        assert!(!dfa.run("ab"));
+        assert!(!dfa.run("abbb"));
+    }
+
+    #[test]
+    fn unsupported() {
+        for pattern in &["^a", "a(?!b)", "(a)\\1"] {
+            let ast = AST::parse(pattern).unwrap();
+            match ast.to_dfa_by_derivatives() {
+                Err(Error(ErrorKind::UnsupportedFeature(_), _)) => {}
+                _ => panic!("{} is not supported", pattern),
+            }
+            match ast.run("a") {
+                Err(Error(ErrorKind::UnsupportedFeature(_), _)) => {}
+                _ => panic!("{} is not supported", pattern),
+            }
+        }
+    }
+
+    #[test]
+    fn classes() {
+        let ast = AST::parse("[a-c]+x|.").unwrap();
+        let dfa = ast.to_dfa_by_derivatives().unwrap();
+
+        for s in &["abcx", "ax", "x", "ä", "", "abc", "dx", "\n"] {
+            assert_eq!(ast.run(s).unwrap(), dfa.run(s), "{}", s);
+        }
+        assert!(ast.run("bbx").unwrap());
+        assert!(!ast.run("\n").unwrap());
+    }
+
+    #[test]
+    fn dfa_is_minimal_for_textbook_example() {
+        let dfa = AST::parse("(?:a|b)*abb")
+            .unwrap()
+            .to_dfa_by_derivatives()
+            .unwrap();
+
+        assert_eq!(dfa.states().len(), 4);
+    }
+}
@@ -1,20 +1,58 @@
+use assertion::{look_behind, LookBehind};
+use class::{previous_char, representative};
+use std::char;
 use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
-use {Automaton, State};
+use {Automaton, Matcher, State, Stream};
 
+#[allow(unexpected_cfgs)]
 mod errors {
     error_chain!{}
 }
 
 use errors::*;
 
+/// State that `DFA::next_state` goes to when there is no transition, and that never accepts.
+pub const DEAD: State = -1;
+
+/// Transitions are keyed by characters, unless the DFA has a partition of the alphabet (see
+/// `class::partition`), in which case they are keyed by the first character of each interval.
 #[derive(Debug)]
 pub struct DFA {
     start: State,
     accept: BTreeSet<State>,
     transitions: BTreeMap<(State, char), State>,
+    partition: Vec<char>,
+    /// For a DFA matching several patterns at once, the patterns each accepting state matches,
+    /// at the end of the input or before a character, keyed like `accept_before`.
+    patterns: BTreeMap<(State, Option<char>), Vec<usize>>,
+    /// States from which an accepting state can be reached.
+    live: BTreeSet<State>,
+    /// Start states for every kind of character a search can start after, see
+    /// `start_state_after`.
+    starts: BTreeMap<LookBehind, State>,
+    /// For a DFA with assertions that look ahead, the states that are accepting when followed
+    /// by a character, keyed like transitions. Missing otherwise, as they are the accepting
+    /// states whatever comes next.
+    accept_before: Option<BTreeSet<(State, char)>>,
+}
+
+/// The two DFAs `find` needs to locate matches, built by `NFA::to_search_dfa`.
+#[derive(Debug)]
+pub struct SearchDFA {
+    /// DFA of the pattern, anchored at the start of the match.
+    forward: DFA,
+    /// DFA of `(?s:.*)` followed by the reversed pattern.
+    reverse: DFA,
+}
+
+/// State of a DFA reading a `Stream`.
+#[derive(Debug)]
+pub struct DFAMatcher<'a> {
+    dfa: &'a DFA,
+    state: State,
 }
 
 impl DFA {
@@ -23,31 +61,273 @@ impl DFA {
         accept: BTreeSet<State>,
         transitions: BTreeMap<(State, char), State>,
     ) -> DFA {
+        let live = DFA::live_states(&accept, &transitions);
         DFA {
             start,
             accept,
             transitions,
+            partition: Vec::new(),
+            patterns: BTreeMap::new(),
+            live,
+            starts: BTreeMap::new(),
+            accept_before: None,
         }
     }
-}
 
-impl Automaton for DFA {
-    fn run(&self, s: &str) -> bool {
+    pub fn with_partition(mut self, partition: Vec<char>) -> DFA {
+        self.partition = partition;
+        self
+    }
+
+    pub fn with_patterns(mut self, patterns: BTreeMap<(State, Option<char>), Vec<usize>>) -> DFA {
+        self.patterns = patterns;
+        self
+    }
+
+    pub fn with_starts(mut self, starts: BTreeMap<LookBehind, State>) -> DFA {
+        self.starts = starts;
+        self
+    }
+
+    pub fn with_accept_before(mut self, accept_before: BTreeSet<(State, char)>) -> DFA {
+        let mut accepting = self.accept.clone();
+        accepting.extend(accept_before.iter().map(|&(state, _)| state));
+        self.live = DFA::live_states(&accepting, &self.transitions);
+        self.accept_before = Some(accept_before);
+        self
+    }
+
+    /// States from which one of `accepting` can be reached.
+    fn live_states(
+        accepting: &BTreeSet<State>,
+        transitions: &BTreeMap<(State, char), State>,
+    ) -> BTreeSet<State> {
+        let mut reverse: BTreeMap<State, BTreeSet<State>> = BTreeMap::new();
+        for (&(s, _), ns) in transitions {
+            reverse.entry(*ns).or_insert(btreeset!()).insert(s);
+        }
+
+        let mut live = accepting.clone();
+        let mut stack: Vec<State> = accepting.iter().cloned().collect();
+        while let Some(state) = stack.pop() {
+            for p in reverse.get(&state).into_iter().flatten() {
+                if live.insert(*p) {
+                    stack.push(*p);
+                }
+            }
+        }
+
+        live
+    }
+
+    /// First state of the DFA. With `next_state`, `is_accepting` and `is_dead`, it can be run
+    /// one character at a time, for input that is not all there yet.
+    pub fn start_state(&self) -> State {
+        self.start
+    }
+
+    /// State to start in right after `previous` in a text, or at its start if there is no
+    /// `previous`. Only DFAs with assertions looking behind, like `^` or `\b`, have other start
+    /// states than `start_state`.
+    pub fn start_state_after(&self, previous: Option<char>) -> State {
+        self.starts
+            .get(&look_behind(previous))
+            .cloned()
+            .unwrap_or(self.start)
+    }
+
+    /// State reached from `state` by reading `c`, which is `DEAD` if there is no transition.
+    pub fn next_state(&self, state: State, c: char) -> State {
+        self.transitions
+            .get(&(state, self.representative(c)))
+            .cloned()
+            .unwrap_or(DEAD)
+    }
+
+    /// Checks if the input read to reach `state` is accepted.
+    pub fn is_accepting(&self, state: State) -> bool {
+        self.accept.contains(&state)
+    }
+
+    /// Checks if the input read to reach `state` is accepted when `c` comes next, which can
+    /// only differ from `is_accepting` for DFAs with assertions looking ahead, like `$` or `\b`.
+    pub fn is_accepting_before(&self, state: State, c: char) -> bool {
+        match self.accept_before {
+            Some(ref accept_before) => accept_before.contains(&(state, self.representative(c))),
+            None => self.is_accepting(state),
+        }
+    }
+
+    /// Checks if no input can lead from `state` to an accepting state, so reading more is
+    /// useless. `DEAD` is always dead, but other states can be too.
+    pub fn is_dead(&self, state: State) -> bool {
+        !self.live.contains(&state)
+    }
+
+    /// State reached from `state` after reading `s`.
+    fn final_state(&self, mut state: State, s: &str) -> State {
+        for c in s.chars() {
+            state = self.next_state(state, c);
+            if state == DEAD {
+                break;
+            }
+        }
+
+        state
+    }
+
+    /// Same as `run` for the part of `s` from byte offset `start` on, with assertions looking
+    /// behind it seeing the character before `start`.
+    pub fn run_from(&self, s: &str, start: usize) -> bool {
+        let state = self.start_state_after(s[..start].chars().next_back());
+        self.is_accepting(self.final_state(state, &s[start..]))
+    }
+
+    /// Starts reading input given in chunks, see `Stream`.
+    pub fn stream(&self) -> Stream<DFAMatcher<'_>> {
+        Stream::new(DFAMatcher {
+            dfa: self,
+            state: self.start,
+        })
+    }
+
+    /// Patterns matching some prefix of `s`, for a DFA with patterns (see `NFA::to_set_dfa`),
+    /// which are those of every accepting state passed along the way.
+    pub fn matches(&self, s: &str) -> Vec<usize> {
+        let mut matched = BTreeSet::new();
         let mut state = self.start;
         for c in s.chars() {
-            if let Some(new_state) = self.transitions.get(&(state, c)) {
-                state = *new_state;
-            } else {
-                return false;
+            matched.extend(self.patterns_at(state, Some(c)));
+            state = self.next_state(state, c);
+            if state == DEAD {
+                return matched.into_iter().collect();
             }
         }
+        matched.extend(self.patterns_at(state, None));
 
-        self.accept.contains(&state)
+        matched.into_iter().collect()
+    }
+
+    /// Patterns `state` matches when `next` comes next, see `is_accepting_before`.
+    fn patterns_at(&self, state: State, next: Option<char>) -> &[usize] {
+        let key = match next {
+            Some(c) if self.accept_before.is_some() => (state, Some(self.representative(c))),
+            _ => (state, None),
+        };
+        self.patterns
+            .get(&key)
+            .map_or(&[], |patterns| &patterns[..])
+    }
+
+    fn representative(&self, c: char) -> char {
+        if self.partition.is_empty() {
+            c
+        } else {
+            representative(&self.partition, c)
+        }
+    }
+
+    fn label(&self, c: char) -> String {
+        let end = match self.partition.binary_search(&c) {
+            Ok(i) if i + 1 < self.partition.len() => previous_char(self.partition[i + 1]),
+            Ok(_) => Some(char::MAX),
+            Err(_) => None,
+        };
+
+        match end {
+            Some(end) if end != c => format!("{}-{}", c.escape_default(), end.escape_default()),
+            _ => c.escape_default().to_string(),
+        }
+    }
+
+    pub fn states(&self) -> BTreeSet<State> {
+        let mut result = BTreeSet::new();
+
+        result.insert(self.start);
+        result.extend(self.accept.iter());
+        for ((s, _), ns) in &self.transitions {
+            result.insert(*s);
+            result.insert(*ns);
+        }
+
+        result
+    }
+}
+
+impl SearchDFA {
+    pub fn new(forward: DFA, reverse: DFA) -> SearchDFA {
+        SearchDFA { forward, reverse }
+    }
+
+    /// Byte offsets of the leftmost-longest match in `s`, in two passes. The reverse DFA reads
+    /// all of `s` backward, and the last time it accepts is where the leftmost match starts.
+    /// The forward DFA reads on from there for as long as it can, and the last time it accepts
+    /// is where the longest match starting there ends.
+    ///
+    /// That is the match POSIX asks for, which is not always the one a backtracking engine
+    /// would find: `a|ab` finds `ab` in `ab`.
+    pub fn find(&self, s: &str) -> Option<(usize, usize)> {
+        let reverse = &self.reverse;
+        let mut state = reverse.start_state();
+        let mut start = None;
+        for (i, c) in s.char_indices().rev() {
+            if reverse.is_accepting_before(state, c) {
+                start = Some(i + c.len_utf8());
+            }
+            state = reverse.next_state(state, c);
+            if reverse.is_dead(state) {
+                break;
+            }
+        }
+        if reverse.is_accepting(state) {
+            start = Some(0);
+        }
+        let start = start?;
+
+        let forward = &self.forward;
+        let mut state = forward.start_state_after(s[..start].chars().next_back());
+        let mut end = None;
+        for (i, c) in s[start..].char_indices() {
+            if forward.is_accepting_before(state, c) {
+                end = Some(start + i);
+            }
+            state = forward.next_state(state, c);
+            if forward.is_dead(state) {
+                return end.map(|end| (start, end));
+            }
+        }
+        if forward.is_accepting(state) {
+            end = Some(s.len());
+        }
+
+        end.map(|end| (start, end))
+    }
+}
+
+impl<'a> Matcher for DFAMatcher<'a> {
+    fn step(&mut self, c: char) {
+        if self.state != DEAD {
+            self.state = self.dfa.next_state(self.state, c);
+        }
+    }
+
+    fn is_accepting(&self) -> bool {
+        self.dfa.is_accepting(self.state)
+    }
+
+    fn is_accepting_before(&self, c: char) -> bool {
+        self.dfa.is_accepting_before(self.state, c)
+    }
+}
+
+impl Automaton for DFA {
+    fn run(&self, s: &str) -> bool {
+        self.is_accepting(self.final_state(self.start, s))
     }
 
     fn write_graphviz(&self, filename: &str) -> Result<()> {
         let path = Path::new(filename);
-        let mut file = File::create(&path).chain_err(|| "unable to create file")?;
+        let mut file = File::create(path).chain_err(|| "unable to create file")?;
 
         writeln!(&mut file, "digraph dfa {{").chain_err(|| "")?;
         writeln!(&mut file, "\trankdir=LR;").chain_err(|| "")?;
@@ -57,7 +337,14 @@ impl Automaton for DFA {
         }
         writeln!(&mut file, ";\n\tnode [shape = circle];").chain_err(|| "")?;
         for (t, s) in &self.transitions {
-            writeln!(&mut file, "\t{} -> {} [ label = \"{}\"]", t.0, s, t.1).chain_err(|| "")?;
+            writeln!(
+                &mut file,
+                "\t{} -> {} [ label = \"{}\"]",
+                t.0,
+                s,
+                self.label(t.1)
+            )
+            .chain_err(|| "")?;
         }
 
         writeln!(&mut file, "}}").chain_err(|| "")?;
@@ -69,6 +356,7 @@ impl Automaton for DFA {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use AST;
 
     #[test]
     fn basic_dfa() {
@@ -87,6 +375,103 @@ mod tests {
         assert!(!dfa.run("ab"));
     }
 
+    #[test]
+    fn partition() {
+        let dfa = DFA::new(
+            0,
+            btreeset!(1),
+            btreemap!(
+                (0, 'a') => 1,
+                (1, 'a') => 1,
+            ),
+        )
+        .with_partition(vec!['\0', 'a', '{']);
+
+        assert!(dfa.run("az"));
+        assert!(!dfa.run("a{"));
+        assert!(!dfa.run("A"));
+        assert_eq!(dfa.label('a'), "a-z");
+        assert_eq!(dfa.label('{'), "{-\\u{10ffff}");
+    }
+
+    #[test]
+    fn patterns() {
+        let dfa = DFA::new(
+            0,
+            btreeset!(1, 2),
+            btreemap!(
+                (0, 'a') => 1,
+                (1, 'b') => 2,
+            ),
+        )
+        .with_patterns(btreemap!((1, None) => vec![0], (2, None) => vec![0, 1]));
+
+        assert_eq!(dfa.matches("a"), vec![0]);
+        assert_eq!(dfa.matches("ab"), vec![0, 1]);
+        assert_eq!(dfa.matches(""), vec![]);
+        assert_eq!(dfa.matches("b"), vec![]);
+        assert_eq!(dfa.matches("abb"), vec![0, 1]);
+    }
+
+    #[test]
+    fn stepping() {
+        let dfa = DFA::new(
+            0,
+            btreeset!(2),
+            btreemap!(
+                (0, 'a') => 1,
+                (1, 'b') => 2,
+                (1, 'c') => 3,
+                (3, 'c') => 3,
+            ),
+        );
+
+        let state = dfa.start_state();
+        assert!(!dfa.is_accepting(state) && !dfa.is_dead(state));
+        let state = dfa.next_state(state, 'a');
+        assert!(!dfa.is_accepting(state) && !dfa.is_dead(state));
+        assert!(dfa.is_accepting(dfa.next_state(state, 'b')));
+        assert!(dfa.is_dead(dfa.next_state(state, 'c')));
+        assert_eq!(dfa.next_state(state, 'd'), DEAD);
+        assert!(dfa.is_dead(DEAD) && !dfa.is_accepting(DEAD));
+        assert_eq!(dfa.next_state(DEAD, 'a'), DEAD);
+
+        let dfa = AST::parse("\\bab")
+            .unwrap()
+            .into_nfa()
+            .unwrap()
+            .to_dfa()
+            .unwrap();
+        assert!(dfa.run_from("x ab", 2));
+        assert!(!dfa.run_from("xab", 1));
+        assert!(dfa.run_from("ab", 0));
+    }
+
+    #[test]
+    fn find() {
+        for &(pattern, s, expected) in &[
+            ("b+", "aabbbc", Some((2, 5))),
+            ("a+", "baaa", Some((1, 4))),
+            ("abcd|c", "abcd", Some((0, 4))),
+            ("a|ab", "xab", Some((1, 3))),
+            ("é+b", "aééb", Some((1, 6))),
+            ("x*", "abc", Some((0, 0))),
+            ("z", "abc", None),
+            ("\\bfoo\\b", "afoo foo", Some((5, 8))),
+            ("a\\B", "ab a", Some((0, 1))),
+            ("(?m)^b", "ab\nb", Some((3, 4))),
+            ("a$", "aa", Some((1, 2))),
+        ] {
+            let dfa = AST::parse(pattern)
+                .unwrap()
+                .into_nfa()
+                .unwrap()
+                .to_search_dfa()
+                .unwrap();
+            assert_eq!(dfa.find(s), expected, "{}", pattern);
+        }
+    }
+
     #[ignore]
     #[test]
     fn graphviz() -> Result<()> {
@@ -0,0 +1,389 @@
+use assertion::{look_behind, LookBehind, AT_START};
+use class::{partition, representative};
+use fnv::FnvHashMap;
+use program::{Inst, InstPtr};
+use sparse::SparseSet;
+use {Automaton, Program};
+
+use errors::*;
+
+/// Marks states of unanchored searches, which start a new thread at every position.
+const UNANCHORED: LookBehind = 1 << 7;
+
+/// A DFA built from a `Program` on the fly, one state at a time, as the input asks for it.
+/// Subset construction can create exponentially many states, but a single run never creates
+/// more than one per input character, and states are cached so that later runs get faster.
+///
+/// Assertions are checked lazily too: a state is the set of instructions threads stopped at
+/// right after consuming a character, together with a few bits describing that character. Zero
+/// width instructions are only followed on the next transition, when the character after the
+/// position is known as well. Lookarounds and backreferences depend on more than that, so they
+/// are rejected.
+///
+/// The number of cached states can be bounded with `with_cache_size`. A full cache is cleared,
+/// keeping only the current state, so runs stay linear but may have to build the same states
+/// again.
+#[derive(Debug, Clone)]
+pub struct LazyDFA {
+    program: Program,
+    partition: Vec<char>,
+    /// Bits of `LookBehind` the assertions of the program depend on.
+    looks_behind: LookBehind,
+    cache_size: usize,
+}
+
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+struct State {
+    insts: Vec<InstPtr>,
+    flags: LookBehind,
+}
+
+/// States and transitions of a `LazyDFA` discovered so far. A cache can be reused for any number
+/// of runs of the DFA it was created for.
+#[derive(Debug, Clone)]
+pub struct LazyCache {
+    states: Vec<State>,
+    ids: FnvHashMap<State, usize>,
+    /// Next state for a state and the first character of an interval of the partition, and
+    /// whether the state reaches `Match` right before that character.
+    transitions: FnvHashMap<(usize, char), (usize, bool)>,
+    stack: Vec<InstPtr>,
+    seen: SparseSet,
+}
+
+impl LazyCache {
+    pub fn new(dfa: &LazyDFA) -> LazyCache {
+        LazyCache {
+            states: Vec::new(),
+            ids: FnvHashMap::default(),
+            transitions: FnvHashMap::default(),
+            stack: Vec::new(),
+            seen: SparseSet::new(dfa.program.insts.len()),
+        }
+    }
+
+    /// Forgets every state but `state`, and returns its new id.
+    fn reset(&mut self, state: usize) -> usize {
+        let state = self.states.swap_remove(state);
+        self.states.clear();
+        self.ids.clear();
+        self.transitions.clear();
+        self.add_state(state)
+    }
+
+    fn add_state(&mut self, state: State) -> usize {
+        if let Some(&id) = self.ids.get(&state) {
+            return id;
+        }
+
+        self.states.push(state.clone());
+        self.ids.insert(state, self.states.len() - 1);
+        self.states.len() - 1
+    }
+}
+
+impl LazyDFA {
+    pub fn new(program: Program) -> Result<LazyDFA> {
+        if !program.looks.is_empty() {
+            bail!(ErrorKind::UnsupportedFeature("lookaround".to_string()));
+        }
+        if program.has_backrefs() {
+            bail!(ErrorKind::UnsupportedFeature("backreference".to_string()));
+        }
+
+        let mut ranges = Vec::new();
+        let mut looks_behind = 0;
+        for inst in &program.insts {
+            match *inst {
+                Inst::Char(c, _) => ranges.push((c, c)),
+                Inst::Range(lo, hi, _) => ranges.push((lo, hi)),
+                Inst::Assert(assertion, _) => {
+                    looks_behind |= assertion.looks_behind();
+                    ranges.extend(assertion.looks_at().ranges());
+                }
+                _ => {}
+            }
+        }
+
+        Ok(LazyDFA {
+            partition: partition(ranges),
+            program,
+            looks_behind,
+            cache_size: usize::MAX,
+        })
+    }
+
+    /// Limits caches to `states` states, or 2 if less, as a transition needs both of its ends.
+    pub fn with_cache_size(mut self, states: usize) -> LazyDFA {
+        self.cache_size = states.max(2);
+        self
+    }
+
+    /// Follows everything that doesn't consume input from `state`, with `next` as the character
+    /// after the position. Returns the consuming instructions reached, and whether `Match` was
+    /// reached.
+    fn closure(
+        &self,
+        cache: &mut LazyCache,
+        state: usize,
+        next: Option<char>,
+    ) -> (Vec<InstPtr>, bool) {
+        let LazyCache {
+            ref states,
+            ref mut stack,
+            ref mut seen,
+            ..
+        } = *cache;
+        let flags = states[state].flags;
+
+        let mut consumers = Vec::new();
+        let mut matched = false;
+        seen.clear();
+        stack.extend(states[state].insts.iter().rev());
+        while let Some(pc) = stack.pop() {
+            if !seen.insert(pc) {
+                continue;
+            }
+
+            match self.program.insts[pc as usize] {
+                Inst::Match => matched = true,
+                Inst::Fail => {}
+                Inst::Jump(next) | Inst::Save(_, next) => stack.push(next),
+                Inst::Split(x, y) => {
+                    stack.push(y);
+                    stack.push(x);
+                }
+                Inst::Assert(assertion, pc) => {
+                    if assertion.holds_after(flags, next) {
+                        stack.push(pc);
+                    }
+                }
+                Inst::Char(..) | Inst::Range(..) => consumers.push(pc),
+                Inst::Look(..) | Inst::Backref(..) => unreachable!("rejected by LazyDFA::new"),
+            }
+        }
+
+        (consumers, matched)
+    }
+
+    fn start(&self, cache: &mut LazyCache, unanchored: bool) -> usize {
+        cache.add_state(State {
+            insts: vec![self.program.start],
+            flags: if unanchored {
+                (AT_START & self.looks_behind) | UNANCHORED
+            } else {
+                AT_START & self.looks_behind
+            },
+        })
+    }
+
+    fn next(&self, cache: &mut LazyCache, state: usize, c: char) -> (usize, bool) {
+        let c = representative(&self.partition, c);
+        if let Some(&next) = cache.transitions.get(&(state, c)) {
+            return next;
+        }
+        let state = if cache.states.len() >= self.cache_size {
+            cache.reset(state)
+        } else {
+            state
+        };
+
+        let (consumers, matched) = self.closure(cache, state, Some(c));
+        let mut insts: Vec<InstPtr> = consumers
+            .into_iter()
+            .filter_map(|pc| self.program.insts[pc as usize].step(c))
+            .collect();
+        let unanchored = cache.states[state].flags & UNANCHORED;
+        if unanchored != 0 {
+            insts.push(self.program.start);
+        }
+        insts.sort();
+        insts.dedup();
+
+        let next = cache.add_state(State {
+            insts,
+            flags: (look_behind(Some(c)) & self.looks_behind) | unanchored,
+        });
+        cache.transitions.insert((state, c), (next, matched));
+        (next, matched)
+    }
+
+    fn accepts_at_end(&self, cache: &mut LazyCache, state: usize) -> bool {
+        self.closure(cache, state, None).1
+    }
+
+    /// Checks if the whole string matches, like `Automaton::run`.
+    pub fn run_with(&self, cache: &mut LazyCache, s: &str) -> bool {
+        let mut state = self.start(cache, false);
+        for c in s.chars() {
+            state = self.next(cache, state, c).0;
+            if cache.states[state].insts.is_empty() {
+                return false;
+            }
+        }
+
+        self.accepts_at_end(cache, state)
+    }
+
+    /// Checks if any substring matches. Stops at the first position where some match ends.
+    pub fn is_match(&self, s: &str) -> bool {
+        self.is_match_with(&mut LazyCache::new(self), s)
+    }
+
+    pub fn is_match_with(&self, cache: &mut LazyCache, s: &str) -> bool {
+        let mut state = self.start(cache, true);
+        for c in s.chars() {
+            let (next, matched) = self.next(cache, state, c);
+            if matched {
+                return true;
+            }
+            state = next;
+        }
+
+        self.accepts_at_end(cache, state)
+    }
+
+    /// Number of states created in `cache` so far.
+    pub fn states(&self, cache: &LazyCache) -> usize {
+        cache.states.len()
+    }
+}
+
+impl Automaton for LazyDFA {
+    fn run(&self, s: &str) -> bool {
+        self.run_with(&mut LazyCache::new(self), s)
+    }
+
+    /// States only exist once some input asked for them, so this writes the underlying program.
+    fn write_graphviz(&self, filename: &str) -> Result<()> {
+        self.program.write_graphviz(filename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Flags, AST};
+
+    fn lazy_dfa(pattern: &str) -> LazyDFA {
+        LazyDFA::new(AST::parse(pattern).unwrap().into_program()).unwrap()
+    }
+
+    #[test]
+    fn run() {
+        let dfa = lazy_dfa("(a|b)*abb");
+
+        assert!(dfa.run("abb"));
+        assert!(dfa.run("babb"));
+        assert!(!dfa.run("ab"));
+        assert!(!dfa.run("abbc"));
+    }
+
+    #[test]
+    fn same_as_program() {
+        for pattern in &["[a-c]+x|.", "(a|ab)(c|bcd)", "a*?b", "(x+x+)+y", "^a|b$"] {
+            let program = AST::parse(pattern).unwrap().into_program();
+            let dfa = LazyDFA::new(program.clone()).unwrap();
+
+            for s in &[
+                "abcx", "x", "ä", "", "abcd", "acd", "xxxy", "aab", "\n", "b",
+            ] {
+                assert_eq!(dfa.run(s), program.run(s), "{} {:?}", pattern, s);
+                assert_eq!(
+                    dfa.is_match(s),
+                    program.find(s).is_some(),
+                    "{} {:?}",
+                    pattern,
+                    s
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn states_are_cached() {
+        let dfa = lazy_dfa("(a|b)*abb");
+        let mut cache = LazyCache::new(&dfa);
+
+        assert!(dfa.run_with(&mut cache, "abababb"));
+        let states = dfa.states(&cache);
+        assert!(dfa.run_with(&mut cache, "abababb"));
+        assert_eq!(dfa.states(&cache), states);
+    }
+
+    #[test]
+    fn cache_size() {
+        // (a|b)*a(a|b)(a|b)(a|b) needs 16 states
+        let dfa = lazy_dfa("(a|b)*a(a|b)(a|b)(a|b)").with_cache_size(5);
+        let mut cache = LazyCache::new(&dfa);
+
+        for s in &["abbbaaabab", "abababbbba", "bbbb", "aaaaaaaaab"] {
+            assert_eq!(
+                dfa.run_with(&mut cache, s),
+                s.chars().rev().nth(3) == Some('a'),
+                "{}",
+                s
+            );
+            assert!(dfa.states(&cache) <= 5);
+        }
+    }
+
+    #[test]
+    fn anchors() {
+        let dfa = lazy_dfa("^ab$");
+
+        assert!(dfa.is_match("ab"));
+        assert!(!dfa.is_match("cab"));
+        assert!(!dfa.is_match("abc"));
+        assert!(!dfa.is_match("a\nab"));
+
+        let dfa = LazyDFA::new(
+            AST::parse_with(
+                "^ab$",
+                Flags {
+                    multi_line: true,
+                    ..Flags::default()
+                },
+            )
+            .unwrap()
+            .into_program(),
+        )
+        .unwrap();
+
+        assert!(dfa.is_match("c\nab\nc"));
+        assert!(dfa.is_match("ab\n"));
+        assert!(!dfa.is_match("cab\nc"));
+        assert!(!dfa.run("ab\n"));
+    }
+
+    #[test]
+    fn word_boundaries() {
+        let dfa = lazy_dfa("\\bis\\b");
+
+        assert!(dfa.is_match("this is it"));
+        assert!(dfa.is_match("is"));
+        assert!(!dfa.is_match("this island"));
+        assert!(!dfa.is_match("isä"));
+
+        let dfa = lazy_dfa("\\Bis\\B");
+
+        assert!(dfa.is_match("mist"));
+        assert!(!dfa.is_match("this island"));
+    }
+
+    #[test]
+    fn unsupported() {
+        let program = AST::parse("a(?=b)").unwrap().into_program();
+
+        match LazyDFA::new(program) {
+            Err(Error(ErrorKind::UnsupportedFeature(_), _)) => {}
+            _ => panic!("lookarounds are not supported"),
+        }
+
+        let program = AST::parse("(a)\\1").unwrap().into_program();
+        match LazyDFA::new(program) {
+            Err(Error(ErrorKind::UnsupportedFeature(_), _)) => {}
+            _ => panic!("backreferences are not supported"),
+        }
+    }
+}
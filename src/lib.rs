@@ -4,18 +4,71 @@ extern crate error_chain;
 extern crate maplit;
 extern crate fnv;
 
+pub mod ahocorasick;
+pub mod assertion;
 pub mod ast;
+mod backtrack;
+pub mod bitparallel;
+pub mod class;
+pub mod compiler;
+mod derivative;
 pub mod dfa;
+pub mod lazy;
+pub mod literal;
 pub mod nfa;
+mod parser;
+pub mod program;
+mod regex;
+mod set;
+mod sparse;
+pub mod stream;
+mod unicode;
+#[rustfmt::skip]
+mod unicode_tables;
 
+pub use ahocorasick::AhoCorasick;
+pub use assertion::{Assertion, Look};
 pub use ast::{Token, AST};
-pub use dfa::DFA;
-pub use nfa::{Transition, NFA};
+pub use bitparallel::BitParallel;
+pub use class::CharClass;
+pub use compiler::{Compiler, Construction};
+pub use dfa::{DFAMatcher, SearchDFA, DFA};
+pub use lazy::{LazyCache, LazyDFA};
+pub use literal::{Literals, Prefilter};
+pub use nfa::{NFAMatcher, StateSet, Transition, NFA};
+pub use parser::Flags;
+pub use program::{Cache, Haystack, Inst, LookTables, Program};
+pub use regex::{
+    CaptureMatches, Captures, Matches, NoExpand, Regex, RegexBuilder, Replacer, Split, SplitN,
+};
+pub use set::{RegexSet, RegexSetBuilder};
+pub use stream::{Matcher, Stream};
 
+// `error_chain!` checks a cfg that only its own build script sets
+#[allow(unexpected_cfgs)]
 mod errors {
-    error_chain!{}
+    error_chain!{
+        errors {
+            /// The pattern uses something the chosen engine can't do.
+            UnsupportedFeature(feature: String) {
+                description("unsupported feature")
+                display("{} is not supported by this engine", feature)
+            }
+            /// An automaton would get bigger than the configured limit.
+            SizeLimit(automaton: String, limit: usize) {
+                description("size limit exceeded")
+                display("{} would have more than {} states", automaton, limit)
+            }
+            /// A stream of bytes is not valid UTF-8 from this offset.
+            InvalidUtf8(offset: usize) {
+                description("invalid UTF-8")
+                display("invalid UTF-8 at byte {}", offset)
+            }
+        }
+    }
 }
 
+pub use errors::{Error, ErrorKind};
 use errors::*;
 
 type State = i32;
@@ -27,7 +80,6 @@ pub trait Automaton {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
 //    use test::Bencher;
 
 //    #[bench]
@@ -60,7 +112,7 @@ mod tests {
 //            ]),
 //        );
 //
-//        let nfa = ast.into_nfa();
+//        let nfa = ast.into_nfa().unwrap();
 //
 //        b.iter(|| {
 //            nfa.run_backtracking("xxxxxxxxxxxxxxx");
@@ -97,7 +149,7 @@ mod tests {
 //            ]),
 //        );
 //
-//        let nfa = ast.into_nfa();
+//        let nfa = ast.into_nfa().unwrap();
 //
 //        b.iter(|| {
 //            nfa.run("xxxxxxxxxxxxxxx");
@@ -134,7 +186,7 @@ mod tests {
 //            ]),
 //        );
 //
-//        let nfa = ast.into_nfa();
+//        let nfa = ast.into_nfa().unwrap();
 //
 //        let dfa = nfa.to_dfa();
 //
@@ -0,0 +1,214 @@
+use ast::Token::*;
+use AST;
+
+/// Strings every match of a pattern contains, found by `AST::literals`. They are only what can
+/// be seen without expanding classes, so they can be shorter than they could be, or empty.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Literals {
+    /// The only string the pattern matches, if it is a literal string.
+    pub exact: Option<String>,
+    /// Every match starts with it.
+    pub prefix: String,
+    /// Every match ends with it.
+    pub suffix: String,
+    /// The longest string found in every match, which can be the prefix or the suffix.
+    pub inner: String,
+}
+
+impl Literals {
+    fn exact(s: String) -> Literals {
+        Literals {
+            exact: Some(s.clone()),
+            prefix: s.clone(),
+            suffix: s.clone(),
+            inner: s,
+        }
+    }
+
+    /// Literals of `self` followed by `other`.
+    fn concat(self, other: Literals) -> Literals {
+        let prefix = match self.exact {
+            Some(ref exact) => exact.clone() + &other.prefix,
+            None => self.prefix.clone(),
+        };
+        let suffix = match other.exact {
+            Some(ref exact) => self.suffix.clone() + exact,
+            None => other.suffix.clone(),
+        };
+        let inner = longest(vec![
+            prefix.clone(),
+            suffix.clone(),
+            self.inner,
+            other.inner,
+            self.suffix.clone() + &other.prefix,
+        ]);
+        let exact = match (self.exact, other.exact) {
+            (Some(a), Some(b)) => Some(a + &b),
+            _ => None,
+        };
+
+        Literals {
+            exact,
+            prefix,
+            suffix,
+            inner,
+        }
+    }
+
+    /// Literals of `self` or `other`.
+    fn or(self, other: Literals) -> Literals {
+        if self.exact.is_some() && self.exact == other.exact {
+            return self;
+        }
+
+        let prefix: String = self
+            .prefix
+            .chars()
+            .zip(other.prefix.chars())
+            .take_while(|&(a, b)| a == b)
+            .map(|(a, _)| a)
+            .collect();
+        let mut suffix: Vec<char> = self
+            .suffix
+            .chars()
+            .rev()
+            .zip(other.suffix.chars().rev())
+            .take_while(|&(a, b)| a == b)
+            .map(|(a, _)| a)
+            .collect();
+        suffix.reverse();
+        let suffix: String = suffix.into_iter().collect();
+
+        Literals {
+            exact: None,
+            inner: longest(vec![prefix.clone(), suffix.clone()]),
+            prefix,
+            suffix,
+        }
+    }
+}
+
+/// The longest of `strings`, the first one if there are several.
+fn longest(strings: Vec<String>) -> String {
+    strings
+        .into_iter()
+        .fold(String::new(), |a, b| if b.len() > a.len() { b } else { a })
+}
+
+impl AST {
+    /// Literal strings all matches of the pattern contain, for `Prefilter`.
+    pub fn literals(&self) -> Literals {
+        match *self.token() {
+            Literal(c) => Literals::exact(c.to_string()),
+            Class(ref class) => match *class.ranges() {
+                [(from, to)] if from == to => Literals::exact(from.to_string()),
+                _ => Literals::default(),
+            },
+            Assertion(_) | Look(_) | Epsilon => Literals::exact(String::new()),
+            Group(..) => self.children()[0].literals(),
+            Plus | LazyPlus => Literals {
+                exact: None,
+                ..self.children()[0].literals()
+            },
+            Concat => self
+                .children()
+                .iter()
+                .map(AST::literals)
+                .fold(Literals::exact(String::new()), Literals::concat),
+            Or => {
+                let mut children = self.children().iter().map(AST::literals);
+                match children.next() {
+                    Some(first) => children.fold(first, Literals::or),
+                    None => Literals::default(),
+                }
+            }
+            // repetitions can match nothing, and cases or backreferences are not literal
+            Star | LazyStar | Backref(..) | CaseInsensitive | Empty => Literals::default(),
+        }
+    }
+}
+
+/// Skips the parts of a text where a pattern can't match, by looking for its literals with the
+/// substring search of the standard library, which is Two-Way, much faster than running an
+/// automaton over every character.
+#[derive(Debug, Clone)]
+pub struct Prefilter {
+    prefix: String,
+    required: String,
+}
+
+impl Prefilter {
+    /// Prefilter for a pattern with these literals, if there is anything to look for.
+    pub fn new(literals: Literals) -> Option<Prefilter> {
+        if literals.inner.is_empty() {
+            return None;
+        }
+
+        Some(Prefilter {
+            prefix: literals.prefix,
+            required: literals.inner,
+        })
+    }
+
+    /// Checks if `s` can't contain a match, because it lacks a string all matches contain.
+    pub fn rejects(&self, s: &str) -> bool {
+        !s.contains(&self.required)
+    }
+
+    /// First byte offset of `s`, from `start` on, where a match can start, which is the next
+    /// occurrence of the prefix, if the pattern has one.
+    pub fn skip(&self, s: &str, start: usize) -> Option<usize> {
+        s[start..].find(&self.prefix).map(|i| start + i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(pattern: &str, exact: Option<&str>, prefix: &str, suffix: &str, inner: &str) {
+        let expected = Literals {
+            exact: exact.map(str::to_string),
+            prefix: prefix.to_string(),
+            suffix: suffix.to_string(),
+            inner: inner.to_string(),
+        };
+        assert_eq!(
+            AST::parse(pattern).unwrap().literals(),
+            expected,
+            "{}",
+            pattern
+        );
+    }
+
+    #[test]
+    fn literals() {
+        check("abc", Some("abc"), "abc", "abc", "abc");
+        check("^a(b)[c]$", Some("abc"), "abc", "abc", "abc");
+        check(".*ERROR: (\\d+)", None, "", "", "ERROR: ");
+        check("foo\\d+bar", None, "foo", "bar", "foo");
+        check("x+abcd\\w", None, "x", "", "xabcd");
+        check("(?:héllo|hélp) world", None, "hél", " world", " world");
+        check("a|b", None, "", "", "");
+        check("(?:ab|cb)c", None, "", "bc", "bc");
+        check("ab|ab", Some("ab"), "ab", "ab", "ab");
+        check("(?i)abc", None, "", "", "");
+        check("(a)\\1b", None, "a", "b", "a");
+        check("a*", None, "", "", "");
+    }
+
+    #[test]
+    fn prefilter() {
+        assert!(Prefilter::new(AST::parse("a*b?").unwrap().literals()).is_none());
+
+        let prefilter = Prefilter::new(AST::parse("ab\\d+cde").unwrap().literals()).unwrap();
+        assert!(prefilter.rejects("ab1cd ab2 cd"));
+        assert!(!prefilter.rejects("ab1cde"));
+        assert_eq!(prefilter.skip("xxab1 ab2", 0), Some(2));
+        assert_eq!(prefilter.skip("xxab1 ab2", 3), Some(6));
+        assert_eq!(prefilter.skip("xxab1 ab2", 7), None);
+
+        let prefilter = Prefilter::new(AST::parse("\\d+cde").unwrap().literals()).unwrap();
+        assert_eq!(prefilter.skip("xx", 1), Some(1));
+    }
+}
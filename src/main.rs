@@ -51,11 +51,14 @@ pub fn main() {
         ])
     );
 
-    let nfa = ast.into_nfa();
-    nfa.write_graphviz("graphs/01nfa.dot");
+    let derivatives_dfa = ast.to_dfa_by_derivatives().unwrap();
+    derivatives_dfa.write_graphviz("graphs/01dfa_derivatives.dot").unwrap();
 
-    let dfa = nfa.to_dfa();
-    dfa.write_graphviz("graphs/01dfa.dot");
+    let nfa = ast.into_nfa().unwrap();
+    nfa.write_graphviz("graphs/01nfa.dot").unwrap();
+
+    let dfa = nfa.to_dfa().unwrap();
+    dfa.write_graphviz("graphs/01dfa.dot").unwrap();
 
     println!("Written!");
 }
\ No newline at end of file
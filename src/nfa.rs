@@ -1,17 +1,21 @@
-use fnv::FnvHashSet;
-use nfa::Transition::{Character, Epsilon};
-use std::collections::btree_map::Entry::Vacant;
+use assertion::{look_behind, LookBehind, AT_START, CONTEXTS};
+use class::partition;
+use fnv::{FnvHashMap, FnvHashSet};
+use nfa::Transition::{Assert, Backref, Character, Epsilon, Look, Range, Save};
+use std::collections::btree_map;
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 use State;
-use {Automaton, DFA};
+use {Assertion, Automaton, LookTables, Matcher, Program, SearchDFA, Stream, DFA};
 
+#[allow(unexpected_cfgs)]
 mod errors {
     error_chain!{}
 }
 
+use assertion;
 use errors::*;
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -19,12 +23,64 @@ pub struct NFA {
     pub start: State,
     pub accept: BTreeSet<State>,
     pub transitions: BTreeMap<(State, Transition), BTreeSet<State>>,
+    /// Automata for the patterns of lookarounds, referred to by `Look` transitions.
+    pub looks: Vec<(assertion::Look, NFA)>,
 }
 
 #[derive(PartialEq, Eq, Debug, Copy, Clone, PartialOrd, Ord)]
 pub enum Transition {
     Epsilon,
     Character(char),
+    /// Any character in the inclusive range.
+    Range(char, char),
+    /// Consumes nothing, like `Epsilon`, but can only be taken where the assertion holds.
+    Assert(Assertion),
+    /// Consumes nothing, but can only be taken where the lookaround with this index holds.
+    Look(u32),
+    /// Consumes nothing, and records the position in the capture slot: `2 * n` for the start
+    /// of group `n`, and `2 * n + 1` for its end.
+    Save(u32),
+    /// Consumes the text last captured by the group, in any case if the flag is set. Only
+    /// backtracking can do that.
+    Backref(u32, bool),
+}
+
+/// Where an NFA run one character at a time is, see `NFA::start_state`: the states it is in,
+/// not yet closed under transitions that consume nothing, and what assertions need to know
+/// about the last character.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct StateSet {
+    states: BTreeSet<State>,
+    behind: LookBehind,
+}
+
+/// State of an NFA reading a `Stream`.
+#[derive(Debug)]
+pub struct NFAMatcher<'a> {
+    nfa: &'a NFA,
+    state: StateSet,
+}
+
+impl Transition {
+    pub fn matches(&self, c: char) -> bool {
+        match *self {
+            Epsilon | Assert(_) | Look(_) | Save(_) | Backref(..) => false,
+            Character(d) => c == d,
+            Range(lo, hi) => lo <= c && c <= hi,
+        }
+    }
+
+    /// Checks if the transition can be taken without consuming anything at position `pos` of
+    /// `chars`, where lookarounds hold as `looks` says.
+    fn passes(&self, chars: &[char], pos: usize, looks: &LookTables) -> bool {
+        match *self {
+            Epsilon | Save(_) => true,
+            Assert(assertion) => assertion.holds_at(chars, pos),
+            Look(look) => looks[look as usize][pos],
+            Character(_) | Range(..) => false,
+            Backref(..) => unreachable!("NFAs with backreferences are backtracked"),
+        }
+    }
 }
 
 impl Default for NFA {
@@ -43,7 +99,49 @@ impl NFA {
             start,
             accept,
             transitions,
+            looks: Vec::new(),
+        }
+    }
+
+    pub fn with_looks(mut self, looks: Vec<(assertion::Look, NFA)>) -> NFA {
+        self.looks = looks;
+        self
+    }
+
+    /// An NFA matching what any of `nfas` matches, along with the accepting states of each of
+    /// them, which are renumbered like all their other states. Lookarounds are renumbered too,
+    /// but capture slots are left alone, so they only make sense for a single NFA.
+    pub fn union(nfas: &[NFA]) -> (NFA, Vec<BTreeSet<State>>) {
+        let mut transitions: BTreeMap<_, BTreeSet<State>> = BTreeMap::new();
+        let mut looks = Vec::new();
+        let mut accepts = Vec::new();
+
+        let mut offset = 1;
+        for nfa in nfas {
+            let look_offset = looks.len() as u32;
+            let renumber = |s: &State| s + offset;
+            transitions
+                .entry((0, Epsilon))
+                .or_insert(btreeset!())
+                .insert(renumber(&nfa.start));
+            for (&(s, t), ns) in &nfa.transitions {
+                let t = match t {
+                    Look(look) => Look(look + look_offset),
+                    t => t,
+                };
+                transitions
+                    .entry((renumber(&s), t))
+                    .or_insert(btreeset!())
+                    .extend(ns.iter().map(renumber));
+            }
+            looks.extend(nfa.looks.iter().cloned());
+            accepts.push(nfa.accept.iter().map(renumber).collect::<BTreeSet<_>>());
+            offset += nfa.states().iter().max().map_or(0, |s| s + 1);
         }
+
+        let accept = accepts.iter().flatten().cloned().collect();
+        let nfa = NFA::new(0, accept, transitions).with_looks(looks);
+        (nfa, accepts)
     }
 
     pub fn states(&self) -> BTreeSet<State> {
@@ -51,7 +149,7 @@ impl NFA {
 
         result.insert(self.start);
         result.extend(self.accept.iter());
-        result.extend(self.transitions.iter().map(|(k, _)| k.0));
+        result.extend(self.transitions.keys().map(|k| k.0));
         for v in self.transitions.values() {
             result.extend(v.iter());
         }
@@ -59,42 +157,120 @@ impl NFA {
         result
     }
 
+    /// Partition of the alphabet into intervals of characters that no transition can tell apart.
+    /// Characters that only assertions can tell apart count too.
+    fn partition(&self) -> Vec<char> {
+        let mut ranges = Vec::new();
+        let mut assertions = BTreeSet::new();
+        for &(_, t) in self.transitions.keys() {
+            match t {
+                Epsilon | Look(_) | Save(_) | Backref(..) => {}
+                Character(c) => ranges.push((c, c)),
+                Range(lo, hi) => ranges.push((lo, hi)),
+                Assert(assertion) => {
+                    assertions.insert(assertion);
+                }
+            }
+        }
+        for assertion in assertions {
+            ranges.extend(assertion.looks_at().ranges());
+        }
+
+        partition(ranges)
+    }
+
+    /// Bits of `LookBehind` the assertions of the NFA depend on.
+    fn looks_behind(&self) -> LookBehind {
+        self.transitions.keys().fold(0, |bits, &(_, t)| match t {
+            Assert(assertion) => bits | assertion.looks_behind(),
+            _ => bits,
+        })
+    }
+
+    /// First characters of intervals of the partition that have any transitions at all.
     fn alphabet(&self) -> BTreeSet<char> {
+        let partition = self.partition();
         let mut result = BTreeSet::new();
 
-        for (_, t) in self.transitions.keys() {
-            if let Character(c) = *t {
+        for c in partition {
+            if self.transitions.keys().any(|&(_, t)| t.matches(c)) {
                 result.insert(c);
-            };
+            }
         }
 
         result
     }
 
-    fn reachable_states(&self, states: &BTreeSet<State>, input: Transition) -> BTreeSet<State> {
+    /// All states reachable from `state` by consuming `c`.
+    fn next_states(&self, state: State, c: char) -> impl Iterator<Item = &State> {
+        self.outgoing(state)
+            .filter(move |&(&(_, t), _)| t.matches(c))
+            .flat_map(|(_, ns)| ns.iter())
+    }
+
+    fn step(&self, states: &BTreeSet<State>, c: char) -> BTreeSet<State> {
         let mut result = BTreeSet::new();
         for s in states {
-            if let Some(next_states) = self.transitions.get(&(*s, input)) {
-                for ns in next_states {
-                    result.insert(ns.clone());
+            result.extend(self.next_states(*s, c));
+        }
+
+        result
+    }
+
+    /// Epsilon closures of the states reachable from `states` by consuming each of `chars`,
+    /// which are sorted, leaving out characters that lead nowhere. Same as `step` and
+    /// `set_epsilon_closure` for every character, but every transition is only looked at once.
+    fn step_all(&self, states: &BTreeSet<State>, chars: &[char]) -> Vec<(char, BTreeSet<State>)> {
+        let mut result: BTreeMap<char, Vec<State>> = BTreeMap::new();
+        for state in states {
+            for (&(_, t), ns) in self.outgoing(*state) {
+                let (lo, hi) = match t {
+                    Character(c) => (c, c),
+                    Range(lo, hi) => (lo, hi),
+                    _ => continue,
+                };
+                let from = chars.partition_point(|&c| c < lo);
+                for &c in chars[from..].iter().take_while(|&&c| c <= hi) {
+                    result.entry(c).or_default().extend(ns);
                 }
             }
         }
 
+        // characters only a class like `.` accepts all lead to the same states
+        let mut closures: FnvHashMap<Vec<State>, BTreeSet<State>> = FnvHashMap::default();
         result
+            .into_iter()
+            .map(|(c, mut next)| {
+                next.sort_unstable();
+                next.dedup();
+                let closure = closures
+                    .entry(next)
+                    .or_insert_with_key(|next| self.set_epsilon_closure(next));
+                (c, closure.clone())
+            })
+            .collect()
     }
 
-    fn set_epsilon_closure(&self, states: &BTreeSet<State>) -> BTreeSet<State> {
-        let mut states = states.clone();
-        loop {
-            let new_states = self.reachable_states(&states, Epsilon);
-            let old_len = states.len();
-            states.extend(new_states);
-            if old_len == states.len() {
-                break;
+    fn set_epsilon_closure<'a, I>(&self, states: I) -> BTreeSet<State>
+    where
+        I: IntoIterator<Item = &'a State>,
+    {
+        let mut stack: Vec<State> = states.into_iter().cloned().collect();
+        let mut result: BTreeSet<State> = stack.iter().cloned().collect();
+        while let Some(state) = stack.pop() {
+            for n in self
+                .transitions
+                .get(&(state, Epsilon))
+                .into_iter()
+                .flatten()
+            {
+                if result.insert(*n) {
+                    stack.push(*n);
+                }
             }
         }
-        states
+
+        result
     }
 
     fn epsilon_closure(&self, state: State) -> BTreeSet<State> {
@@ -120,61 +296,437 @@ impl NFA {
         done
     }
 
+    /// Extends `states` with everything reachable through epsilons and through assertions that
+    /// hold between a character described by `behind` and `next`.
+    fn assertion_closure(
+        &self,
+        states: &BTreeSet<State>,
+        behind: LookBehind,
+        next: Option<char>,
+    ) -> BTreeSet<State> {
+        let mut result = states.clone();
+        let mut stack: Vec<State> = states.iter().cloned().collect();
+        while let Some(state) = stack.pop() {
+            for (&(_, t), ns) in self.outgoing(state) {
+                let zero_width = match t {
+                    Epsilon | Save(_) => true,
+                    Assert(assertion) => assertion.holds_after(behind, next),
+                    _ => false,
+                };
+                if zero_width {
+                    for n in ns {
+                        if result.insert(*n) {
+                            stack.push(*n);
+                        }
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
     fn find_accept_state(&self, states: &BTreeSet<State>) -> Option<State> {
         states.intersection(&self.accept).cloned().nth(0)
     }
 
-    pub fn to_dfa(&self) -> DFA {
-        let alphabet = self.alphabet();
+    /// Subset construction. Assertions can only be checked once the next character is known, so
+    /// they are followed on the way out of a DFA state, which is a set of NFA states closed under
+    /// epsilons together with the bits of `LookBehind` the assertions need. Without assertions
+    /// no bits are needed at all, and this is the textbook construction.
+    ///
+    /// Lookarounds would need to know about the rest of the input, and backreferences about the
+    /// input so far, neither of which a DFA state can, so they are rejected. Captures are
+    /// ignored.
+    pub fn to_dfa(&self) -> Result<DFA> {
+        self.to_dfa_with_limit(usize::MAX)
+    }
+
+    /// Same as `to_dfa`, but gives up with `ErrorKind::SizeLimit` as soon as the DFA gets more
+    /// than `limit` states, which the subset construction can do with only a few NFA states:
+    /// `(a|b)*a(a|b)^n` needs `2^n` of them.
+    pub fn to_dfa_with_limit(&self, limit: usize) -> Result<DFA> {
+        self.subset_construction(limit, |_, _, states| {
+            self.find_accept_state(states).is_some()
+        })
+    }
+
+    /// Same as `to_dfa_with_limit` for a union of NFAs built by `NFA::union`: every accepting
+    /// state of the DFA records which sets of `accepts` it has states of, at the end of the
+    /// input and before each character, see `DFA::matches`.
+    pub fn to_set_dfa(&self, accepts: &[BTreeSet<State>], limit: usize) -> Result<DFA> {
+        let mut patterns = BTreeMap::new();
+        let dfa = self.subset_construction(limit, |id, next, states| {
+            let matched: Vec<usize> = accepts
+                .iter()
+                .enumerate()
+                .filter(|&(_, accept)| !accept.is_disjoint(states))
+                .map(|(i, _)| i)
+                .collect();
+            if matched.is_empty() {
+                return false;
+            }
+            patterns.insert((id, next), matched);
+            true
+        })?;
+
+        Ok(dfa.with_patterns(patterns))
+    }
+
+    /// DFAs for `SearchDFA::find`: the DFA of `(?s:.*)` followed by the reversed NFA, which finds
+    /// where matches start, along with the DFA of the NFA, which finds where they end.
+    pub fn to_search_dfa(&self) -> Result<SearchDFA> {
+        let reverse = self.reverse()?.unanchored().to_dfa()?;
+        Ok(SearchDFA::new(self.to_dfa()?, reverse))
+    }
+
+    /// The NFA preceded by `(?s:.*)`, which matches strings with a suffix the NFA matches.
+    pub fn unanchored(&self) -> NFA {
+        let start = self.states().iter().max().map_or(0, |s| s + 1);
+        let mut transitions = self.transitions.clone();
+        transitions.insert((start, Range('\0', char::MAX)), btreeset!(start));
+        transitions.insert((start, Epsilon), btreeset!(self.start));
 
-        let mut states = BTreeMap::new();
+        NFA::new(start, self.accept.clone(), transitions).with_looks(self.looks.clone())
+    }
+
+    /// NFA matching the reversed strings: every transition goes the other way, from a new start
+    /// state to the former accepting states, and the former start state is the only accepting
+    /// one. Assertions are reversed too, so `^` becomes `$`.
+    ///
+    /// Captures are dropped, and lookarounds and backreferences are rejected, since a
+    /// lookahead would have to become a lookbehind, and a backreference would come before its
+    /// group.
+    pub fn reverse(&self) -> Result<NFA> {
+        self.check_forward_only()?;
+
+        let start = self.states().iter().max().map_or(0, |s| s + 1);
+        let mut transitions: BTreeMap<_, BTreeSet<State>> = BTreeMap::new();
+        for (&(s, t), ns) in &self.transitions {
+            let t = match t {
+                Save(_) => Epsilon,
+                Assert(assertion) => Assert(assertion.reverse()),
+                t => t,
+            };
+            for n in ns {
+                transitions.entry((*n, t)).or_insert(btreeset!()).insert(s);
+            }
+        }
+        transitions.insert((start, Epsilon), self.accept.clone());
+
+        Ok(NFA::new(start, btreeset!(self.start), transitions))
+    }
+
+    /// The subset construction behind `to_dfa_with_limit`. `accepting` is given every DFA state
+    /// along with the NFA states it has at the end of the input, and for DFAs with assertions
+    /// before each character too, and says whether the DFA state is accepting there.
+    fn subset_construction<F>(&self, limit: usize, mut accepting: F) -> Result<DFA>
+    where
+        F: FnMut(State, Option<char>, &BTreeSet<State>) -> bool,
+    {
+        self.check_forward_only()?;
+        if self.transitions.keys().any(|&(_, t)| matches!(t, Save(_))) {
+            return self.without_saves().subset_construction(limit, accepting);
+        }
+
+        let partition = self.partition();
+        let looks_behind = self.looks_behind();
+        let has_assertions = self
+            .transitions
+            .keys()
+            .any(|&(_, t)| matches!(t, Assert(_)));
+        // without assertions, characters without transitions lead nowhere, and a DFA state is
+        // accepting or not whatever comes next
+        let chars: Vec<char> = if has_assertions {
+            partition.clone()
+        } else {
+            self.alphabet().into_iter().collect()
+        };
+
+        let mut states = FnvHashMap::default();
         let mut accept = BTreeSet::new();
+        let mut accept_before = BTreeSet::new();
         let mut transitions = BTreeMap::new();
+        let mut queue = VecDeque::new();
+
+        let add = |states: &mut FnvHashMap<_, State>,
+                   queue: &mut VecDeque<_>,
+                   state: (BTreeSet<State>, LookBehind)|
+         -> Result<State> {
+            if let Some(id) = states.get(&state) {
+                return Ok(*id);
+            }
+            if states.len() >= limit {
+                bail!(ErrorKind::SizeLimit("DFA".to_string(), limit));
+            }
 
-        let mut id = 0;
-        let mut get_id = || {
-            id += 1;
-            id - 1
+            let id = states.len() as State;
+            states.insert(state.clone(), id);
+            queue.push_back((id, state));
+            Ok(id)
         };
-        let mut queue = VecDeque::new();
 
-        let init_state = self.epsilon_closure(self.start);
-        queue.push_back((get_id(), init_state.clone()));
-        states.insert(init_state.into_iter().collect(), 0);
-        while let Some((cur_id, cur_state)) = queue.pop_front() {
-            for a in &alphabet {
-                let mut new_state = self.reachable_states(&cur_state, Character(*a));
-                new_state = self.set_epsilon_closure(&new_state);
-
-                if !new_state.is_empty() {
-                    if let Vacant(entry) = states.entry(new_state.clone()) {
-                        let id = get_id();
-                        if self.find_accept_state(&new_state).is_some() {
-                            accept.insert(id);
+        // a search can start anywhere in a text, after any kind of character
+        let mut starts = BTreeMap::new();
+        for &context in &CONTEXTS {
+            let init_state = (self.epsilon_closure(self.start), context & looks_behind);
+            starts.insert(context, add(&mut states, &mut queue, init_state)?);
+        }
+
+        while let Some((cur_id, (cur_states, behind))) = queue.pop_front() {
+            if accepting(
+                cur_id,
+                None,
+                &self.assertion_closure(&cur_states, behind, None),
+            ) {
+                accept.insert(cur_id);
+            }
+            // without assertions, states are closed already and the same whatever comes next
+            let steps = if has_assertions {
+                chars
+                    .iter()
+                    .map(|&a| {
+                        let closure = self.assertion_closure(&cur_states, behind, Some(a));
+                        if accepting(cur_id, Some(a), &closure) {
+                            accept_before.insert((cur_id, a));
                         }
-                        queue.push_back((id, new_state.clone()));
-                        entry.insert(id);
+                        (a, self.set_epsilon_closure(&self.step(&closure, a)))
+                    })
+                    .collect()
+            } else {
+                self.step_all(&cur_states, &chars)
+            };
+
+            for (a, new_states) in steps {
+                if !new_states.is_empty() {
+                    let new_state = (new_states, look_behind(Some(a)) & looks_behind);
+                    let id = add(&mut states, &mut queue, new_state)?;
+                    transitions.insert((cur_id, a), id);
+                }
+            }
+        }
+
+        let dfa = DFA::new(0, accept, transitions)
+            .with_partition(partition)
+            .with_starts(starts);
+        Ok(if has_assertions {
+            dfa.with_accept_before(accept_before)
+        } else {
+            dfa
+        })
+    }
+
+    /// Rejects lookarounds and backreferences, which only work on the whole input, see `to_dfa`.
+    fn check_forward_only(&self) -> Result<()> {
+        if !self.looks.is_empty() {
+            bail!(ErrorKind::UnsupportedFeature("lookaround".to_string()));
+        }
+        if self.has_backrefs() {
+            bail!(ErrorKind::UnsupportedFeature("backreference".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// First states of the NFA. With `next_state`, `is_accepting` and `is_dead`, it can be run
+    /// one character at a time, for input that is not all there yet. Like the Thompson
+    /// simulation, this keeps track of the set of states the NFA is in, but assertions are
+    /// followed like in the subset construction, once the next character is known. Lookarounds
+    /// and backreferences are rejected, for the same reasons as in `to_dfa`.
+    pub fn start_state(&self) -> Result<StateSet> {
+        self.check_forward_only()?;
+        Ok(StateSet {
+            states: btreeset!(self.start),
+            behind: AT_START,
+        })
+    }
+
+    /// States reached from `set` by reading `c`.
+    pub fn next_state(&self, set: &StateSet, c: char) -> StateSet {
+        let closure = self.assertion_closure(&set.states, set.behind, Some(c));
+        StateSet {
+            states: self.step(&closure, c),
+            behind: look_behind(Some(c)),
+        }
+    }
+
+    /// Checks if the input read to reach `set` is accepted.
+    pub fn is_accepting(&self, set: &StateSet) -> bool {
+        let closure = self.assertion_closure(&set.states, set.behind, None);
+        self.find_accept_state(&closure).is_some()
+    }
+
+    /// Checks if the input read to reach `set` is accepted when `c` comes next, for assertions
+    /// looking ahead.
+    pub fn is_accepting_before(&self, set: &StateSet, c: char) -> bool {
+        let closure = self.assertion_closure(&set.states, set.behind, Some(c));
+        self.find_accept_state(&closure).is_some()
+    }
+
+    /// Checks if no input can lead from `set` to an accepting state, so reading more is
+    /// useless. Assertions are assumed to hold, so some dead sets go unnoticed.
+    pub fn is_dead(&self, set: &StateSet) -> bool {
+        let mut reachable = set.states.clone();
+        let mut stack: Vec<State> = set.states.iter().cloned().collect();
+        while let Some(state) = stack.pop() {
+            if self.accept.contains(&state) {
+                return false;
+            }
+            for (_, ns) in self.outgoing(state) {
+                for n in ns {
+                    if reachable.insert(*n) {
+                        stack.push(*n);
+                    }
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Starts reading input given in chunks, see `Stream` and `start_state`.
+    pub fn stream(&self) -> Result<Stream<NFAMatcher<'_>>> {
+        Ok(Stream::new(NFAMatcher {
+            nfa: self,
+            state: self.start_state()?,
+        }))
+    }
+
+    pub fn has_backrefs(&self) -> bool {
+        self.transitions
+            .keys()
+            .any(|&(_, t)| matches!(t, Backref(..)))
+    }
+
+    /// The same NFA with `Save` transitions turned into epsilons, for constructions that don't
+    /// care about captures.
+    fn without_saves(&self) -> NFA {
+        let mut transitions: BTreeMap<_, BTreeSet<State>> = BTreeMap::new();
+        for (&(s, t), ns) in &self.transitions {
+            let t = match t {
+                Save(_) => Epsilon,
+                t => t,
+            };
+            transitions.entry((s, t)).or_insert(btreeset!()).extend(ns);
+        }
+
+        NFA::new(self.start, self.accept.clone(), transitions).with_looks(self.looks.clone())
+    }
+
+    /// Builds an equivalent NFA without epsilon transitions. Every state gets the character
+    /// transitions of its whole epsilon closure, and becomes accepting if the closure contains an
+    /// accepting state. States that were only reachable through epsilons are pruned afterwards.
+    ///
+    /// Other transitions that don't consume anything are kept as they are, since whether they can
+    /// be taken depends on the input, or they record captures.
+    pub fn remove_epsilons(&self) -> NFA {
+        let mut accept = BTreeSet::new();
+        let mut transitions: BTreeMap<_, BTreeSet<State>> = BTreeMap::new();
+
+        for state in self.states() {
+            let closure = self.epsilon_closure(state);
+            if self.find_accept_state(&closure).is_some() {
+                accept.insert(state);
+            }
+
+            for s in &closure {
+                for ((_, t), ns) in self.outgoing(*s) {
+                    if *t != Epsilon {
+                        transitions
+                            .entry((state, *t))
+                            .or_insert(btreeset!())
+                            .extend(ns);
+                    }
+                }
+            }
+        }
+
+        NFA::new(self.start, accept, transitions)
+            .with_looks(self.looks.clone())
+            .prune()
+    }
+
+    /// Removes states that are unreachable from the start state, and dead states from which no
+    /// accepting state can be reached.
+    pub fn prune(&self) -> NFA {
+        let mut reachable = btreeset!(self.start);
+        let mut queue = VecDeque::new();
+        queue.push_back(self.start);
+        while let Some(state) = queue.pop_front() {
+            for (_, ns) in self.outgoing(state) {
+                for n in ns {
+                    if reachable.insert(*n) {
+                        queue.push_back(*n);
                     }
+                }
+            }
+        }
 
-                    let id = &states[&new_state];
-                    transitions.insert((cur_id, *a), *id);
+        let mut reverse: BTreeMap<State, BTreeSet<State>> = BTreeMap::new();
+        for ((s, _), ns) in &self.transitions {
+            for n in ns {
+                reverse.entry(*n).or_insert(btreeset!()).insert(*s);
+            }
+        }
+
+        let mut alive: BTreeSet<State> = self.accept.intersection(&reachable).cloned().collect();
+        let mut queue: VecDeque<State> = alive.iter().cloned().collect();
+        while let Some(state) = queue.pop_front() {
+            if let Some(previous) = reverse.get(&state) {
+                for p in previous {
+                    if reachable.contains(p) && alive.insert(*p) {
+                        queue.push_back(*p);
+                    }
                 }
             }
         }
 
-        DFA::new(0, accept, transitions)
+        let mut transitions = BTreeMap::new();
+        for (&(s, t), ns) in &self.transitions {
+            if !alive.contains(&s) {
+                continue;
+            }
+
+            let ns: BTreeSet<State> = ns.intersection(&alive).cloned().collect();
+            if !ns.is_empty() {
+                transitions.insert((s, t), ns);
+            }
+        }
+
+        let accept = self.accept.intersection(&alive).cloned().collect();
+        NFA::new(self.start, accept, transitions).with_looks(self.looks.clone())
+    }
+
+    /// All transitions going out of `state`.
+    pub fn outgoing(
+        &self,
+        state: State,
+    ) -> btree_map::Range<'_, (State, Transition), BTreeSet<State>> {
+        self.transitions
+            .range((state, Epsilon)..(state + 1, Epsilon))
+    }
+
+    pub fn to_program(&self) -> Program {
+        Program::from(self)
     }
 
     pub fn run_backtracking(&self, s: &str) -> bool {
+        if self.has_backrefs() {
+            return self.to_program().run_bounded_backtracking(s);
+        }
+
         let s: Vec<char> = s.chars().collect();
+        let looks = self.look_tables(&s);
 
         let mut queue = VecDeque::new();
         queue.push_back((self.start, 0));
 
         while let Some((state, pos)) = queue.pop_front() {
-            if let Some(set) = self.transitions.get(&(state, Epsilon)) {
-                for item in set {
-                    queue.push_back((*item, pos));
+            for (&(_, t), ns) in self.outgoing(state) {
+                if t.passes(&s, pos, &looks) {
+                    for item in ns {
+                        queue.push_back((*item, pos));
+                    }
                 }
             }
 
@@ -182,8 +734,8 @@ impl NFA {
                 if self.accept.contains(&state) {
                     return true;
                 }
-            } else if let Some(set) = self.transitions.get(&(state, Character(s[pos]))) {
-                for item in set {
+            } else {
+                for item in self.next_states(state, s[pos]) {
                     queue.push_back((*item, pos + 1));
                 }
             }
@@ -191,7 +743,56 @@ impl NFA {
         false
     }
 
-    fn epsilon_closure_thompson(&self, state: State) -> FnvHashSet<State> {
+    /// States the Thompson simulation is in once it has read all of `s`.
+    fn final_states(&self, s: &str) -> FnvHashSet<State> {
+        let chars: Vec<char> = s.chars().collect();
+        let looks = self.look_tables(&chars);
+        let mut clist = self.epsilon_closure_thompson(self.start, &chars, 0, &looks);
+
+        for pos in 0..chars.len() {
+            clist = self.step_thompson(&clist, &chars, pos, &looks);
+        }
+
+        clist
+    }
+
+    /// Indices of the sets of `accepts` that the NFA is in a state of at some point of reading
+    /// `s`, found by the Thompson simulation. This is what `DFA::matches` does for the DFA built
+    /// by `to_set_dfa`.
+    pub fn matches(&self, s: &str, accepts: &[BTreeSet<State>]) -> Vec<usize> {
+        let chars: Vec<char> = s.chars().collect();
+        let looks = self.look_tables(&chars);
+        let mut matched = vec![false; accepts.len()];
+        let mut clist = self.epsilon_closure_thompson(self.start, &chars, 0, &looks);
+        for pos in 0..=chars.len() {
+            for (i, accept) in accepts.iter().enumerate() {
+                matched[i] = matched[i] || accept.iter().any(|s| clist.contains(s));
+            }
+            if pos < chars.len() {
+                clist = self.step_thompson(&clist, &chars, pos, &looks);
+            }
+        }
+
+        (0..accepts.len()).filter(|&i| matched[i]).collect()
+    }
+
+    /// Tables of lookarounds for `chars`, which are computed by programs.
+    fn look_tables(&self, chars: &[char]) -> LookTables {
+        if self.looks.is_empty() {
+            return Vec::new();
+        }
+
+        Program::from(self).look_tables(chars)
+    }
+
+    /// States reachable from `state` without consuming anything, at position `pos` of `chars`.
+    fn epsilon_closure_thompson(
+        &self,
+        state: State,
+        chars: &[char],
+        pos: usize,
+        looks: &LookTables,
+    ) -> FnvHashSet<State> {
         let mut done = FnvHashSet::default();
         let mut not_done = FnvHashSet::default();
         not_done.insert(state);
@@ -199,11 +800,9 @@ impl NFA {
         while !not_done.is_empty() {
             let mut to_do = FnvHashSet::default();
             for s in &not_done {
-                if let Some(epsilon_from_s) = self.transitions.get(&(*s, Epsilon)) {
-                    for new_state in epsilon_from_s.iter() {
-                        if !done.contains(new_state) {
-                            to_do.insert(*new_state);
-                        }
+                for (&(_, t), ns) in self.outgoing(*s) {
+                    if t.passes(chars, pos, looks) {
+                        to_do.extend(ns.iter().filter(|n| !done.contains(*n)));
                     }
                 }
                 done.insert(*s);
@@ -214,14 +813,19 @@ impl NFA {
         done
     }
 
-    fn step_thompson(&self, clist: &FnvHashSet<State>, c: char) -> FnvHashSet<State> {
+    /// Consumes the character at `pos`.
+    fn step_thompson(
+        &self,
+        clist: &FnvHashSet<State>,
+        chars: &[char],
+        pos: usize,
+        looks: &LookTables,
+    ) -> FnvHashSet<State> {
         let mut result = FnvHashSet::default();
 
         for s in clist.iter() {
-            if let Some(ns) = self.transitions.get(&(*s, Character(c))) {
-                for n in ns {
-                    result.extend(self.epsilon_closure_thompson(*n));
-                }
+            for n in self.next_states(*s, chars[pos]) {
+                result.extend(self.epsilon_closure_thompson(*n, chars, pos + 1, looks));
             }
         }
 
@@ -229,25 +833,35 @@ impl NFA {
     }
 }
 
-impl Automaton for NFA {
-    fn run(&self, s: &str) -> bool {
-        let mut clist: FnvHashSet<State> = FnvHashSet::default();
+impl<'a> Matcher for NFAMatcher<'a> {
+    fn step(&mut self, c: char) {
+        self.state = self.nfa.next_state(&self.state, c);
+    }
 
-        clist.insert(self.start);
+    fn is_accepting(&self) -> bool {
+        self.nfa.is_accepting(&self.state)
+    }
 
-        for c in s.chars() {
-            //            println!("{}", clist.len());
-            clist = self.step_thompson(&clist, c);
+    fn is_accepting_before(&self, c: char) -> bool {
+        self.nfa.is_accepting_before(&self.state, c)
+    }
+}
+
+impl Automaton for NFA {
+    /// Thompson simulation, or backtracking for NFAs with backreferences, which compilers only
+    /// build as programs but can be put together by hand.
+    fn run(&self, s: &str) -> bool {
+        if self.has_backrefs() {
+            return self.to_program().run_bounded_backtracking(s);
         }
 
-        //        println!("{:?}", clist);
-        let accept: FnvHashSet<_> = self.accept.iter().cloned().collect();
-        clist.intersection(&accept).count() != 0
+        let clist = self.final_states(s);
+        self.accept.iter().any(|s| clist.contains(s))
     }
 
     fn write_graphviz(&self, filename: &str) -> Result<()> {
         let path = Path::new(filename);
-        let mut file = File::create(&path).chain_err(|| "unable to create file")?;
+        let mut file = File::create(path).chain_err(|| "unable to create file")?;
 
         writeln!(&mut file, "digraph nfa {{").chain_err(|| "")?;
         writeln!(&mut file, "\trankdir=LR;").chain_err(|| "")?;
@@ -261,6 +875,12 @@ impl Automaton for NFA {
                 let label = match t.1 {
                     Epsilon => "ε".to_string(),
                     Character(c) => c.to_string(),
+                    Range(lo, hi) => format!("{}-{}", lo, hi),
+                    Assert(assertion) => assertion.to_string(),
+                    Look(look) => format!("{}#{}", self.looks[look as usize].0, look),
+                    Save(slot) => format!("save {}", slot),
+                    Backref(group, false) => format!("\\{}", group),
+                    Backref(group, true) => format!("(?i:\\{})", group),
                 };
 
                 writeln!(&mut file, "\t{} -> {} [ label = \"{}\"]", t.0, s, label)
@@ -277,6 +897,7 @@ impl Automaton for NFA {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use AST;
 
     #[test]
     fn basic_nfa() {
@@ -400,12 +1021,205 @@ mod tests {
             ),
         );
 
-        let dfa = nfa.to_dfa();
+        let dfa = nfa.to_dfa().unwrap();
 
         assert!(!dfa.run("baa"));
         assert!(dfa.run("baab"));
     }
 
+    #[test]
+    fn dfa_classes() {
+        let nfa = AST::parse("[a-c]+x|.").unwrap().into_nfa().unwrap();
+        let dfa = nfa.to_dfa().unwrap();
+
+        for s in &["abcx", "ax", "x", "ä", "", "abc", "dx", "\n"] {
+            assert_eq!(nfa.run(s), dfa.run(s), "{}", s);
+        }
+        assert!(dfa.run("bbx"));
+        assert!(dfa.run("ä"));
+    }
+
+    #[test]
+    fn anchors() {
+        let nfa = AST::parse("(^|a)b$").unwrap().into_nfa().unwrap();
+        let without_epsilons = nfa.remove_epsilons();
+
+        for s in &["b", "ab", "", "bb", "abb"] {
+            assert_eq!(nfa.run(s), nfa.run_backtracking(s), "{}", s);
+            assert_eq!(nfa.run(s), without_epsilons.run(s), "{}", s);
+        }
+        assert!(nfa.run("ab"));
+        assert!(!nfa.run("bb"));
+    }
+
+    #[test]
+    fn dfa_assertions() {
+        for pattern in &["(^|a)b$", "a\\b.*", "\\B.\\B", "(\\bx|y)*\\b"] {
+            let nfa = AST::parse(pattern).unwrap().into_nfa().unwrap();
+            let dfa = nfa.to_dfa().unwrap();
+
+            for s in &["ab", "b", "a", "a b", "ab c", "aäb", "xyx", "x y", "yx", ""] {
+                assert_eq!(nfa.run(s), dfa.run(s), "{} {:?}", pattern, s);
+            }
+        }
+
+        let dfa = AST::parse("a\\b.*")
+            .unwrap()
+            .into_nfa()
+            .unwrap()
+            .to_dfa()
+            .unwrap();
+        assert!(dfa.run("a b"));
+        assert!(!dfa.run("ab"));
+    }
+
+    #[test]
+    fn dfa_size_limit() {
+        // (a|b)*a(a|b)^4 needs 2^5 states
+        let nfa = AST::parse("[ab]*a[ab][ab][ab][ab]")
+            .unwrap()
+            .into_nfa()
+            .unwrap();
+
+        let states = nfa.to_dfa().unwrap().states().len();
+        assert!(states >= 32);
+        assert!(nfa.to_dfa_with_limit(states).is_ok());
+        match nfa.to_dfa_with_limit(31) {
+            Err(Error(ErrorKind::SizeLimit(_, 31), _)) => {}
+            _ => panic!("the DFA has more than 31 states"),
+        }
+    }
+
+    #[test]
+    fn union() {
+        let nfas: Vec<NFA> = ["ab", "a+", "(?=b)b|c"]
+            .iter()
+            .map(|p| AST::parse(p).unwrap().into_nfa().unwrap())
+            .collect();
+        let (nfa, accepts) = NFA::union(&nfas);
+
+        assert_eq!(accepts.len(), 3);
+        assert_eq!(nfa.looks.len(), 1);
+        assert!(nfa.run("ab") && nfa.run("aaa") && nfa.run("b") && !nfa.run("ba"));
+        assert_eq!(nfa.matches("a", &accepts), vec![1]);
+        assert_eq!(nfa.matches("ab", &accepts), vec![0, 1]);
+        assert_eq!(nfa.matches("b", &accepts), vec![2]);
+        assert!(nfa.to_set_dfa(&accepts, usize::MAX).is_err());
+
+        let (nfa, accepts) = NFA::union(&nfas[..2]);
+        let dfa = nfa.to_set_dfa(&accepts, usize::MAX).unwrap();
+        for s in &["", "a", "aa", "ab", "abb", "b", "ba"] {
+            assert_eq!(dfa.matches(s), nfa.matches(s, &accepts), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn reverse() {
+        for pattern in &["ab+c", "(a|bc)*d?", "(?m)^a+\\b$", "\\Ba\\b"] {
+            let nfa = AST::parse(pattern).unwrap().into_nfa().unwrap();
+            let reverse = nfa.reverse().unwrap();
+
+            for s in &["abbc", "ac", "abcad", "bca", "aa", "a\naa\n", "ba", "ba b"] {
+                let reversed: String = s.chars().rev().collect();
+                assert_eq!(reverse.run(&reversed), nfa.run(s), "{} {:?}", pattern, s);
+            }
+        }
+        assert!(AST::parse("a(?=b)")
+            .unwrap()
+            .into_nfa()
+            .unwrap()
+            .reverse()
+            .is_err());
+    }
+
+    #[test]
+    fn stepping() {
+        let nfa = AST::parse("(a)b+\\b|ac").unwrap().into_nfa().unwrap();
+
+        let set = nfa.start_state().unwrap();
+        assert!(!nfa.is_accepting(&set) && !nfa.is_dead(&set));
+        let set = nfa.next_state(&set, 'a');
+        assert!(!nfa.is_accepting(&set) && !nfa.is_dead(&set));
+        let set = nfa.next_state(&set, 'b');
+        assert!(nfa.is_accepting(&set) && !nfa.is_dead(&set));
+        assert!(nfa.is_accepting(&nfa.next_state(&set, 'b')));
+        assert!(nfa.is_dead(&nfa.next_state(&set, 'c')));
+        assert_eq!(nfa.next_state(&set, 'b'), nfa.next_state(&set, 'b'));
+
+        // (a)\1
+        let nfa = NFA::new(
+            0,
+            btreeset!(4),
+            btreemap!(
+                (0, Save(2)) => btreeset!(1),
+                (1, Character('a')) => btreeset!(2),
+                (2, Save(3)) => btreeset!(3),
+                (3, Backref(1, false)) => btreeset!(4)
+            ),
+        );
+        match nfa.start_state() {
+            Err(Error(ErrorKind::UnsupportedFeature(..), _)) => {}
+            _ => panic!("backreferences need the whole input"),
+        }
+        assert!(nfa.run("aa") && nfa.run_backtracking("aa"));
+        assert!(!nfa.run("ab") && !nfa.run_backtracking("a"));
+    }
+
+    #[test]
+    fn remove_epsilons() {
+        let nfa = NFA::new(
+            0,
+            btreeset!(9),
+            btreemap!(
+                (0, Epsilon) => btreeset!(1, 3),
+                (1, Character('a')) => btreeset!(2),
+                (2, Epsilon) => btreeset!(5),
+                (3, Character('b')) => btreeset!(4),
+                (4, Epsilon) => btreeset!(5),
+                (5, Epsilon) => btreeset!(6, 8),
+                (6, Character('a')) => btreeset!(7),
+                (7, Epsilon) => btreeset!(6, 8),
+                (8, Character('b')) => btreeset!(9),
+            ),
+        );
+
+        let without_epsilons = nfa.remove_epsilons();
+
+        assert!(without_epsilons
+            .transitions
+            .keys()
+            .all(|&(_, t)| t != Epsilon));
+        for s in &["ab", "bb", "aaab", "baab", "", "a", "abba", "baa"] {
+            assert_eq!(nfa.run(s), without_epsilons.run(s));
+        }
+    }
+
+    #[test]
+    fn prune() {
+        let nfa = NFA::new(
+            0,
+            btreeset!(2),
+            btreemap!(
+                (0, Character('a')) => btreeset!(1, 3),
+                (1, Character('b')) => btreeset!(2),
+                (3, Character('c')) => btreeset!(3),
+                (4, Character('d')) => btreeset!(2),
+            ),
+        );
+
+        assert_eq!(
+            nfa.prune(),
+            NFA::new(
+                0,
+                btreeset!(2),
+                btreemap!(
+                    (0, Character('a')) => btreeset!(1),
+                    (1, Character('b')) => btreeset!(2),
+                ),
+            )
+        );
+    }
+
     #[test]
     fn default() {
         assert_eq!(NFA::default(), NFA::new(0, btreeset!(0), btreemap!()));
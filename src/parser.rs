@@ -0,0 +1,826 @@
+use ast::Token::*;
+use {Assertion, CharClass, Look, AST};
+
+use std::collections::HashMap;
+
+use errors::*;
+
+/// Recursive descent parser for the pattern syntax:
+///
+/// ```text
+/// alternation := concat ('|' concat)*
+/// concat      := repeat*
+/// repeat      := atom (('*' | '+' | '?') '?'?)*
+/// atom        := literal | '\' escape | '\' digit+ | '\k<' name '>' | '\' perl | '.' | '^'
+///              | '$' | '[' '^'? (item ('-' item)?)+ ']' | '(' group alternation ')'
+/// item        := literal | '\' escape | '\' perl
+/// perl        := [dDwWsS] | [pP] letter | [pP] '{' '^'? property '}'
+/// group       := ('?:' | '?=' | '?!' | '?<=' | '?<!' | '?<' name '>' | '?P<' name '>'
+///                 | '?' flags ':')?
+///              | '(?' flags ')'
+/// flags       := [imsxa]* ('-' [imsxa]*)?
+/// ```
+///
+/// `?` is desugared into `Or`, and a trailing `?` makes a quantifier lazy.
+/// Groups are capturing unless they start with `?`, and are numbered by their opening
+/// parenthesis. Backreferences can only refer to groups opened before them, and are rejected
+/// inside lookarounds, whose tables are computed by automata.
+///
+/// Flags set by `(?flags)` last until the end of the enclosing group, and those of
+/// `(?flags:...)` only apply inside it. In verbose mode, whitespace and comments from `#` to
+/// the end of the line are skipped everywhere but in classes.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    flags: Flags,
+    /// Number of capturing groups opened so far.
+    groups: u32,
+    names: HashMap<String, u32>,
+    /// Number of lookarounds the parser is inside of.
+    looks: u32,
+}
+
+enum GroupKind {
+    NonCapturing,
+    /// `(?flags)`, which has no contents.
+    SetFlags,
+    Capturing(Option<String>),
+    Look(Look),
+}
+
+/// Options changing the meaning of a pattern, which can also be set inside it with `(?imsx)`.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub struct Flags {
+    /// `i`: literals and classes match all cases of their characters. Literals are wrapped in
+    /// `CaseInsensitive` for compilers to expand, classes are folded right away.
+    pub case_insensitive: bool,
+    /// `m`: `^` and `$` match at the start and end of every line, not only of the whole input.
+    pub multi_line: bool,
+    /// `s`: `.` matches `\n` too.
+    pub dot_matches_new_line: bool,
+    /// `x`: whitespace and `#` comments are ignored.
+    pub verbose: bool,
+    /// `a`: `\d`, `\w` and `\s` only match ASCII characters.
+    pub ascii_only: bool,
+}
+
+impl Flags {
+    fn get_mut(&mut self, flag: char) -> Option<&mut bool> {
+        match flag {
+            'i' => Some(&mut self.case_insensitive),
+            'm' => Some(&mut self.multi_line),
+            's' => Some(&mut self.dot_matches_new_line),
+            'x' => Some(&mut self.verbose),
+            'a' => Some(&mut self.ascii_only),
+            _ => None,
+        }
+    }
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Skips whitespace and comments in verbose mode.
+    fn skip_ignored(&mut self) {
+        while self.flags.verbose {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => self.pos += 1,
+                Some('#') => while self.next().is_some_and(|c| c != '\n') {},
+                _ => break,
+            }
+        }
+    }
+
+    fn alternation(&mut self) -> Result<AST> {
+        let mut alternatives = vec![self.concat()?];
+        while self.eat('|') {
+            alternatives.push(self.concat()?);
+        }
+
+        if alternatives.len() == 1 {
+            Ok(alternatives.pop().unwrap())
+        } else {
+            Ok(AST::new(Or, Some(alternatives)))
+        }
+    }
+
+    fn concat(&mut self) -> Result<AST> {
+        let mut items = Vec::new();
+        self.skip_ignored();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let item = self.repeat()?;
+            // flag groups like `(?i)` leave nothing behind
+            if *item.token() != Epsilon {
+                items.push(item);
+            }
+            self.skip_ignored();
+        }
+
+        match items.len() {
+            0 => Ok(AST::epsilon()),
+            1 => Ok(items.pop().unwrap()),
+            _ => Ok(AST::new(Concat, Some(items))),
+        }
+    }
+
+    fn repeat(&mut self) -> Result<AST> {
+        let mut ast = self.atom()?;
+        self.skip_ignored();
+        while let Some(c) = self.peek() {
+            if c != '*' && c != '+' && c != '?' {
+                break;
+            }
+            self.pos += 1;
+            let lazy = self.eat('?');
+
+            ast = match (c, lazy) {
+                ('*', false) => AST::new(Star, Some(vec![ast])),
+                ('*', true) => AST::new(LazyStar, Some(vec![ast])),
+                ('+', false) => AST::new(Plus, Some(vec![ast])),
+                ('+', true) => AST::new(LazyPlus, Some(vec![ast])),
+                (_, false) => AST::new(Or, Some(vec![ast, AST::epsilon()])),
+                (_, true) => AST::new(Or, Some(vec![AST::epsilon(), ast])),
+            };
+            self.skip_ignored();
+        }
+
+        Ok(ast)
+    }
+
+    fn atom(&mut self) -> Result<AST> {
+        let start = self.pos;
+        match self.next() {
+            Some('(') => {
+                let outer = self.flags;
+                let kind = self.group_kind(start)?;
+                let index = match kind {
+                    GroupKind::Capturing(ref name) => {
+                        self.groups += 1;
+                        if let Some(ref name) = *name {
+                            if self.names.insert(name.clone(), self.groups).is_some() {
+                                bail!("duplicate group name '{}' at {}", name, start);
+                            }
+                        }
+                        self.groups
+                    }
+                    GroupKind::Look(_) => {
+                        self.looks += 1;
+                        0
+                    }
+                    GroupKind::NonCapturing => 0,
+                    GroupKind::SetFlags => return Ok(AST::epsilon()),
+                };
+
+                let ast = self.alternation()?;
+                if !self.eat(')') {
+                    bail!("unclosed group at {}", start);
+                }
+                self.flags = outer;
+                Ok(match kind {
+                    GroupKind::NonCapturing | GroupKind::SetFlags => ast,
+                    GroupKind::Capturing(name) => AST::group(index, name, ast),
+                    GroupKind::Look(look) => {
+                        self.looks -= 1;
+                        AST::look(look, ast)
+                    }
+                })
+            }
+            Some('[') => {
+                let class = self.class()?;
+                Ok(AST::new(Class(class), None))
+            }
+            Some('.') if self.flags.dot_matches_new_line => {
+                Ok(AST::new(Class(CharClass::any()), None))
+            }
+            Some('.') => Ok(AST::new(
+                Class(CharClass::new(vec![('\n', '\n')]).negate()),
+                None,
+            )),
+            Some('^') if self.flags.multi_line => Ok(AST::assertion(Assertion::StartLine)),
+            Some('^') => Ok(AST::assertion(Assertion::StartText)),
+            Some('$') if self.flags.multi_line => Ok(AST::assertion(Assertion::EndLine)),
+            Some('$') => Ok(AST::assertion(Assertion::EndText)),
+            Some('\\') if self.eat('A') => Ok(AST::assertion(Assertion::StartText)),
+            Some('\\') if self.eat('z') => Ok(AST::assertion(Assertion::EndText)),
+            Some('\\') if self.eat('b') => Ok(AST::assertion(if self.flags.ascii_only {
+                Assertion::AsciiWordBoundary
+            } else {
+                Assertion::WordBoundary
+            })),
+            Some('\\') if self.eat('B') => Ok(AST::assertion(if self.flags.ascii_only {
+                Assertion::AsciiNotWordBoundary
+            } else {
+                Assertion::NotWordBoundary
+            })),
+            Some('\\') if self.peek().is_some_and(|c| c.is_ascii_digit()) => {
+                let digits = self.pos;
+                while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    self.pos += 1;
+                }
+                let number: String = self.chars[digits..self.pos].iter().collect();
+                match number.parse() {
+                    Ok(index) if index > 0 => self.backref(index, start),
+                    _ => bail!("invalid backreference '\\{}' at {}", number, start),
+                }
+            }
+            Some('\\') if self.eat('k') => {
+                if !self.eat('<') {
+                    bail!("expected '<' after '\\k' at {}", start);
+                }
+                let name = self.name(start)?;
+                match self.names.get(&name) {
+                    Some(&index) => self.backref(index, start),
+                    None => bail!("undefined group name '{}' at {}", name, start),
+                }
+            }
+            Some('\\') if self.peek().is_some_and(|c| "dDwWsSpP".contains(c)) => {
+                let class = self.class_escape()?;
+                Ok(AST::new(Class(class), None))
+            }
+            Some('\\') => {
+                let c = self.escape()?;
+                Ok(self.cased(AST::literal(c)))
+            }
+            Some(c @ '*') | Some(c @ '+') | Some(c @ '?') => {
+                bail!("nothing to repeat with '{}' at {}", c, start)
+            }
+            Some(')') => bail!("unopened group at {}", start),
+            Some(c) => Ok(self.cased(AST::literal(c))),
+            None => bail!("unexpected end of pattern"),
+        }
+    }
+
+    fn cased(&self, ast: AST) -> AST {
+        if self.flags.case_insensitive {
+            AST::case_insensitive(ast)
+        } else {
+            ast
+        }
+    }
+
+    /// Parses what follows `(`, up to the start of the group's contents. Flags are set right
+    /// away.
+    fn group_kind(&mut self, start: usize) -> Result<GroupKind> {
+        if !self.eat('?') {
+            return Ok(GroupKind::Capturing(None));
+        }
+        if self.peek().is_some_and(|c| "imsxa-".contains(c)) {
+            return self.flag_group(start);
+        }
+
+        Ok(match (self.next(), self.next()) {
+            (Some(':'), _) => {
+                self.pos -= 1;
+                GroupKind::NonCapturing
+            }
+            (Some('='), _) => {
+                self.pos -= 1;
+                GroupKind::Look(Look::Ahead)
+            }
+            (Some('!'), _) => {
+                self.pos -= 1;
+                GroupKind::Look(Look::NotAhead)
+            }
+            (Some('<'), Some('=')) => GroupKind::Look(Look::Behind),
+            (Some('<'), Some('!')) => GroupKind::Look(Look::NotBehind),
+            (Some('<'), Some(_)) => {
+                self.pos -= 1;
+                GroupKind::Capturing(Some(self.name(start)?))
+            }
+            (Some('P'), Some('<')) => GroupKind::Capturing(Some(self.name(start)?)),
+            _ => bail!("unsupported group syntax at {}", start),
+        })
+    }
+
+    /// Parses flags after `(?`, up to the closing `)` or `:`.
+    fn flag_group(&mut self, start: usize) -> Result<GroupKind> {
+        let mut value = true;
+        let mut any = false;
+        loop {
+            match self.next() {
+                Some(':') if any => return Ok(GroupKind::NonCapturing),
+                Some(')') if any => return Ok(GroupKind::SetFlags),
+                Some('-') if value => value = false,
+                Some(c) => match self.flags.get_mut(c) {
+                    Some(flag) => {
+                        *flag = value;
+                        any = true;
+                    }
+                    None => bail!("unknown flag '{}' at {}", c, start),
+                },
+                None => bail!("unclosed group at {}", start),
+            }
+        }
+    }
+
+    /// Parses a group name, after the opening `<`.
+    fn name(&mut self, start: usize) -> Result<String> {
+        let from = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            self.pos += 1;
+        }
+        let name: String = self.chars[from..self.pos].iter().collect();
+
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) || !self.eat('>') {
+            bail!("invalid group name at {}", start);
+        }
+        Ok(name)
+    }
+
+    fn backref(&self, index: u32, start: usize) -> Result<AST> {
+        if index > self.groups {
+            bail!("backreference to undefined group {} at {}", index, start);
+        }
+        if self.looks > 0 {
+            bail!(
+                "backreferences inside lookarounds are not supported at {}",
+                start
+            );
+        }
+        Ok(self.cased(AST::backref(index)))
+    }
+
+    fn escape(&mut self) -> Result<char> {
+        match self.next() {
+            Some('n') => Ok('\n'),
+            Some('t') => Ok('\t'),
+            Some('r') => Ok('\r'),
+            Some(c) if !c.is_alphanumeric() => Ok(c),
+            Some(c) => bail!("unknown escape '\\{}' at {}", c, self.pos - 2),
+            None => bail!("unexpected end of pattern after '\\'"),
+        }
+    }
+
+    /// Parses a Perl class or a Unicode property after `\`, folded in case-insensitive mode.
+    fn class_escape(&mut self) -> Result<CharClass> {
+        let start = self.pos - 1;
+        let kind = self.next().unwrap();
+        let (class, negated) = if kind == 'p' || kind == 'P' {
+            let name: String = if self.eat('{') {
+                let from = self.pos;
+                while self.peek().is_some_and(|c| c != '}') {
+                    self.pos += 1;
+                }
+                if !self.eat('}') {
+                    bail!("unclosed property at {}", start);
+                }
+                self.chars[from..self.pos - 1].iter().collect()
+            } else {
+                match self.next() {
+                    Some(c) => c.to_string(),
+                    None => bail!("unexpected end of pattern after '\\{}'", kind),
+                }
+            };
+
+            let (name, caret) = match name.strip_prefix('^') {
+                Some(name) => (name, true),
+                None => (name.as_str(), false),
+            };
+            match CharClass::property(name) {
+                Some(class) => (class, (kind == 'P') != caret),
+                None => bail!("unknown property '{}' at {}", name, start),
+            }
+        } else {
+            let lower = kind.to_ascii_lowercase();
+            let class = CharClass::perl(lower, self.flags.ascii_only).unwrap();
+            (class, kind != lower)
+        };
+
+        let class = if self.flags.case_insensitive {
+            class.fold_case()
+        } else {
+            class
+        };
+        Ok(if negated { class.negate() } else { class })
+    }
+
+    /// Parses a bracketed class, after the opening `[`.
+    fn class(&mut self) -> Result<CharClass> {
+        let start = self.pos - 1;
+        let negated = self.eat('^');
+
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            if self.peek() == Some('\\')
+                && self
+                    .chars
+                    .get(self.pos + 1)
+                    .is_some_and(|&c| "dDwWsSpP".contains(c))
+            {
+                self.pos += 1;
+                ranges.extend(self.class_escape()?.ranges());
+                first = false;
+                continue;
+            }
+
+            let lo = match self.next() {
+                Some(']') if !first => break,
+                Some('\\') => self.escape()?,
+                Some(c) => c,
+                None => bail!("unclosed class at {}", start),
+            };
+            first = false;
+
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                let hi = match self.next() {
+                    Some('\\') => self.escape()?,
+                    Some(c) => c,
+                    None => bail!("unclosed class at {}", start),
+                };
+                if hi < lo {
+                    bail!("invalid class range {}-{} at {}", lo, hi, start);
+                }
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+
+        // `(?i)[^a]` doesn't match `A`, so classes are folded before being negated
+        let mut class = CharClass::new(ranges);
+        if self.flags.case_insensitive {
+            class = class.fold_case();
+        }
+        Ok(if negated { class.negate() } else { class })
+    }
+}
+
+impl AST {
+    pub fn parse(pattern: &str) -> Result<AST> {
+        AST::parse_with(pattern, Flags::default())
+    }
+
+    pub fn parse_with(pattern: &str, flags: Flags) -> Result<AST> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+            flags,
+            groups: 0,
+            names: HashMap::new(),
+            looks: 0,
+        };
+
+        let ast = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            bail!("unopened group at {}", parser.pos);
+        }
+
+        Ok(ast)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(pattern: &str) -> AST {
+        AST::parse(pattern).unwrap()
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(parse("a"), AST::literal('a'));
+        assert_eq!(
+            parse("a\\*"),
+            AST::new(Concat, Some(vec![AST::literal('a'), AST::literal('*')]))
+        );
+        assert_eq!(parse(""), AST::epsilon());
+    }
+
+    #[test]
+    fn alternation() {
+        assert_eq!(
+            parse("b|a|"),
+            AST::new(
+                Or,
+                Some(vec![AST::literal('b'), AST::literal('a'), AST::epsilon()])
+            )
+        );
+    }
+
+    #[test]
+    fn quantifiers() {
+        let a = AST::literal('a');
+
+        assert_eq!(parse("a*"), AST::new(Star, Some(vec![a.clone()])));
+        assert_eq!(parse("a*?"), AST::new(LazyStar, Some(vec![a.clone()])));
+        assert_eq!(parse("a+"), AST::new(Plus, Some(vec![a.clone()])));
+        assert_eq!(parse("a+?"), AST::new(LazyPlus, Some(vec![a.clone()])));
+
+        // the repeated subtree is not copied, so nesting doesn't double the size
+        let nested = "(".repeat(40) + "a" + &")+".repeat(40);
+        assert!(parse(&nested).into_nfa().unwrap().states().len() < 40 * 8);
+        assert_eq!(
+            parse("a?"),
+            AST::new(Or, Some(vec![a.clone(), AST::epsilon()]))
+        );
+        assert_eq!(
+            parse("a??"),
+            AST::new(Or, Some(vec![AST::epsilon(), a.clone()]))
+        );
+    }
+
+    #[test]
+    fn groups() {
+        assert_eq!(
+            parse("(?:ab)*"),
+            AST::new(
+                Star,
+                Some(vec![AST::new(
+                    Concat,
+                    Some(vec![AST::literal('a'), AST::literal('b')])
+                )])
+            )
+        );
+    }
+
+    #[test]
+    fn captures() {
+        let a = AST::literal('a');
+
+        assert_eq!(
+            parse("(a)(?<x>a)(?P<y>a)"),
+            AST::new(
+                Concat,
+                Some(vec![
+                    AST::group(1, None, a.clone()),
+                    AST::group(2, Some("x".to_string()), a.clone()),
+                    AST::group(3, Some("y".to_string()), a.clone()),
+                ])
+            )
+        );
+        assert_eq!(
+            parse("((a))"),
+            AST::group(1, None, AST::group(2, None, a.clone()))
+        );
+        assert!(AST::parse("(?<x>a)(?<x>b)").is_err());
+        assert!(AST::parse("(?<1x>a)").is_err());
+        assert!(AST::parse("(?<>a)").is_err());
+    }
+
+    #[test]
+    fn backrefs() {
+        assert_eq!(
+            parse("(a)\\1"),
+            AST::new(
+                Concat,
+                Some(vec![
+                    AST::group(1, None, AST::literal('a')),
+                    AST::backref(1),
+                ])
+            )
+        );
+        assert_eq!(
+            parse("(?<x>a)\\k<x>"),
+            AST::new(
+                Concat,
+                Some(vec![
+                    AST::group(1, Some("x".to_string()), AST::literal('a')),
+                    AST::backref(1),
+                ])
+            )
+        );
+        assert!(AST::parse("\\0").is_err());
+        assert!(AST::parse("(a)\\2").is_err());
+        assert!(AST::parse("\\1(a)").is_err());
+        assert!(AST::parse("\\k<x>").is_err());
+        assert!(AST::parse("(a)(?=\\1)").is_err());
+        assert!(AST::parse("[\\1]").is_err());
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(
+            parse("[a-cx]"),
+            AST::new(Class(CharClass::new(vec![('a', 'c'), ('x', 'x')])), None)
+        );
+        assert_eq!(
+            parse("[]-]"),
+            AST::new(Class(CharClass::new(vec![(']', ']'), ('-', '-')])), None)
+        );
+        assert_eq!(
+            parse("[^a]"),
+            AST::new(Class(CharClass::new(vec![('a', 'a')]).negate()), None)
+        );
+    }
+
+    #[test]
+    fn anchors() {
+        let multi_line = Flags {
+            multi_line: true,
+            ..Flags::default()
+        };
+
+        assert_eq!(
+            parse("^a$"),
+            AST::new(
+                Concat,
+                Some(vec![
+                    AST::assertion(Assertion::StartText),
+                    AST::literal('a'),
+                    AST::assertion(Assertion::EndText),
+                ])
+            )
+        );
+        assert_eq!(
+            AST::parse_with("^$", multi_line).unwrap(),
+            AST::new(
+                Concat,
+                Some(vec![
+                    AST::assertion(Assertion::StartLine),
+                    AST::assertion(Assertion::EndLine),
+                ])
+            )
+        );
+        assert_eq!(
+            AST::parse_with("\\A\\z", multi_line).unwrap(),
+            AST::new(
+                Concat,
+                Some(vec![
+                    AST::assertion(Assertion::StartText),
+                    AST::assertion(Assertion::EndText),
+                ])
+            )
+        );
+    }
+
+    #[test]
+    fn word_boundaries() {
+        assert_eq!(
+            parse("\\ba\\B"),
+            AST::new(
+                Concat,
+                Some(vec![
+                    AST::assertion(Assertion::WordBoundary),
+                    AST::literal('a'),
+                    AST::assertion(Assertion::NotWordBoundary),
+                ])
+            )
+        );
+        assert!(AST::parse("[\\b]").is_err());
+        assert_eq!(
+            parse("(?a)\\b"),
+            AST::assertion(Assertion::AsciiWordBoundary)
+        );
+    }
+
+    #[test]
+    fn lookarounds() {
+        assert_eq!(
+            parse("(?=a)(?!b)(?<=c)(?<!d)"),
+            AST::new(
+                Concat,
+                Some(vec![
+                    AST::look(Look::Ahead, AST::literal('a')),
+                    AST::look(Look::NotAhead, AST::literal('b')),
+                    AST::look(Look::Behind, AST::literal('c')),
+                    AST::look(Look::NotBehind, AST::literal('d')),
+                ])
+            )
+        );
+        assert!(AST::parse("(?<a)").is_err());
+    }
+
+    #[test]
+    fn flags() {
+        let a = AST::literal('a');
+        let b = AST::literal('b');
+
+        assert_eq!(
+            parse("a(?i)b[c]"),
+            AST::new(
+                Concat,
+                Some(vec![
+                    a.clone(),
+                    AST::case_insensitive(b.clone()),
+                    AST::new(Class(CharClass::new(vec![('C', 'C'), ('c', 'c')])), None),
+                ])
+            )
+        );
+        assert_eq!(
+            parse("((?i)a)b"),
+            AST::new(
+                Concat,
+                Some(vec![
+                    AST::group(1, None, AST::case_insensitive(a.clone())),
+                    b.clone(),
+                ])
+            )
+        );
+        assert_eq!(
+            parse("(?i:a)b"),
+            AST::new(
+                Concat,
+                Some(vec![AST::case_insensitive(a.clone()), b.clone()])
+            )
+        );
+        assert_eq!(
+            AST::parse_with(
+                "(?-i)a",
+                Flags {
+                    case_insensitive: true,
+                    ..Flags::default()
+                }
+            )
+            .unwrap(),
+            a.clone()
+        );
+        assert_eq!(parse("(?s)."), AST::new(Class(CharClass::any()), None));
+        assert_eq!(parse("(?m)^"), AST::assertion(Assertion::StartLine));
+        assert!(AST::parse("(?q)").is_err());
+        assert!(AST::parse("(?i").is_err());
+        assert!(AST::parse("(?)").is_err());
+    }
+
+    #[test]
+    fn verbose() {
+        assert_eq!(
+            parse("(?x) a # comment\n b * | \\ "),
+            AST::new(
+                Or,
+                Some(vec![
+                    AST::new(
+                        Concat,
+                        Some(vec![
+                            AST::literal('a'),
+                            AST::new(Star, Some(vec![AST::literal('b')])),
+                        ])
+                    ),
+                    AST::literal(' '),
+                ])
+            )
+        );
+        assert_eq!(
+            parse("(?x)[ ]"),
+            AST::new(Class(CharClass::new(vec![(' ', ' ')])), None)
+        );
+    }
+
+    #[test]
+    fn unicode_classes() {
+        let class = |pattern| match *parse(pattern).token() {
+            Class(ref class) => class.clone(),
+            _ => panic!("{} is not a class", pattern),
+        };
+        let greek = CharClass::property("Greek").unwrap();
+
+        assert_eq!(class("\\p{Greek}"), greek);
+        assert_eq!(class("\\P{Greek}"), greek.negate());
+        assert_eq!(class("\\p{^Greek}"), greek.negate());
+        assert_eq!(class("\\pL"), CharClass::property("L").unwrap());
+        assert_eq!(class("\\d"), CharClass::perl('d', false).unwrap());
+        assert_eq!(
+            class("(?a)\\W"),
+            CharClass::perl('w', true).unwrap().negate()
+        );
+        assert_eq!(
+            class("[\\p{Greek}\\d_]"),
+            greek
+                .union(&CharClass::perl('d', false).unwrap())
+                .union(&CharClass::new(vec![('_', '_')]))
+        );
+        assert_eq!(
+            class("[^\\s]"),
+            CharClass::perl('s', false).unwrap().negate()
+        );
+        assert!(class("(?i)\\p{Lu}").contains('a'));
+        assert!(AST::parse("\\p{Klingon}").is_err());
+        assert!(AST::parse("\\p{Greek").is_err());
+        assert!(AST::parse("\\p").is_err());
+    }
+
+    #[test]
+    fn errors() {
+        assert!(AST::parse("(a").is_err());
+        assert!(AST::parse("a)").is_err());
+        assert!(AST::parse("*").is_err());
+        assert!(AST::parse("[a").is_err());
+        assert!(AST::parse("[z-a]").is_err());
+        assert!(AST::parse("\\q").is_err());
+    }
+}
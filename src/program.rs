@@ -0,0 +1,1112 @@
+use backtrack::Backtracker;
+use nfa::Transition::{self, Assert, Backref, Character, Epsilon, Range, Save};
+use sparse::SparseSet;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::Write;
+use std::mem;
+use std::path::Path;
+use {Assertion, Automaton, Look, NFA};
+
+use errors::*;
+
+/// Index of an instruction in a `Program`.
+pub type InstPtr = u32;
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Inst {
+    /// The input is accepted if it ends here.
+    Match,
+    /// A dead end, nothing can be matched from here.
+    Fail,
+    Char(char, InstPtr),
+    /// Matches any character in the inclusive range.
+    Range(char, char, InstPtr),
+    /// Continues with both instructions, the first one has priority.
+    Split(InstPtr, InstPtr),
+    Jump(InstPtr),
+    /// Continues without consuming anything if the assertion holds.
+    Assert(Assertion, InstPtr),
+    /// Continues without consuming anything if the lookaround with this index holds.
+    Look(u32, InstPtr),
+    /// Records the position in a capture slot and continues.
+    Save(u32, InstPtr),
+    /// Consumes the text last captured by the group, in any case if the flag is set. Only
+    /// backtracking can do that.
+    Backref(u32, bool, InstPtr),
+}
+
+impl Inst {
+    /// If the instruction consumes `c`, returns the instruction to continue with.
+    pub fn step(&self, c: char) -> Option<InstPtr> {
+        match *self {
+            Inst::Char(d, next) if c == d => Some(next),
+            Inst::Range(lo, hi, next) if lo <= c && c <= hi => Some(next),
+            _ => None,
+        }
+    }
+}
+
+/// A compact form of an `NFA`: states are dense indices into a vector of instructions, so
+/// simulation does not need any map lookups.
+///
+/// Every state of the original NFA keeps its position (in the order of state ids), and states
+/// with several outgoing transitions are followed by chains of `Split`s placed after them.
+///
+/// The Pike VM can't follow backreferences, so programs with them are searched by backtracking
+/// instead, whichever method is called.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Program {
+    pub insts: Vec<Inst>,
+    pub start: InstPtr,
+    /// Programs for the patterns of lookarounds, referred to by `Look` instructions.
+    pub looks: Vec<(Look, Program)>,
+}
+
+/// Whether each lookaround of a program holds at each position of some input, counted in
+/// characters: `tables[look][pos]`.
+pub type LookTables = Vec<Vec<bool>>;
+
+/// A text along with what searches of a program need to know about it, computed once so that
+/// searches starting further and further into the text don't go over all of it every time.
+#[derive(Debug, Clone)]
+pub struct Haystack<'t> {
+    pub text: &'t str,
+    pub chars: Vec<char>,
+    /// Byte offset of every character, followed by the length of the text.
+    pub offsets: Vec<usize>,
+    /// Where the lookarounds of the program hold in the text.
+    pub looks: LookTables,
+}
+
+impl<'t> Haystack<'t> {
+    pub fn new(program: &Program, text: &'t str) -> Haystack<'t> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut offsets: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+        offsets.push(text.len());
+
+        Haystack {
+            text,
+            looks: program.look_tables(&chars),
+            chars,
+            offsets,
+        }
+    }
+
+    /// Position in characters of the byte offset `offset`, which must be a character boundary.
+    pub fn position(&self, offset: usize) -> usize {
+        self.offsets
+            .binary_search(&offset)
+            .expect("offset at a character boundary")
+    }
+}
+
+/// A position in the input, with everything zero-width instructions need to know about it.
+#[derive(Clone, Copy)]
+struct At<'a> {
+    previous: Option<char>,
+    next: Option<char>,
+    /// Position in characters.
+    pos: usize,
+    /// Position in bytes, which is what `Save` records.
+    offset: usize,
+    looks: &'a LookTables,
+}
+
+/// Threads of a simulation: a set of instructions in priority order, and the capture slots of
+/// each thread, `slots` of them per instruction.
+#[derive(Debug, Clone)]
+struct Threads {
+    set: SparseSet,
+    slots: usize,
+    caps: Vec<Option<usize>>,
+}
+
+impl Threads {
+    fn new(size: usize, slots: usize) -> Threads {
+        Threads {
+            set: SparseSet::new(size),
+            slots,
+            caps: vec![None; size * slots],
+        }
+    }
+
+    fn caps(&self, pc: InstPtr) -> &[Option<usize>] {
+        let from = pc as usize * self.slots;
+        &self.caps[from..from + self.slots]
+    }
+}
+
+/// Work left while following zero-width instructions in `Program::add_thread`.
+#[derive(Debug, Clone)]
+enum Frame {
+    Explore(InstPtr),
+    /// Puts back the previous value of a capture slot, once everything after a `Save` is added.
+    Restore(usize, Option<usize>),
+}
+
+/// Buffers used by `Program::run_with`, `Program::find_with` and `Program::captures_with`. A
+/// cache can be reused for any number of runs of the program it was created for.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    clist: Threads,
+    nlist: Threads,
+    stack: Vec<Frame>,
+    caps: Vec<Option<usize>>,
+    /// For programs with backreferences.
+    backtracker: Backtracker,
+}
+
+impl Cache {
+    pub fn new(program: &Program) -> Cache {
+        Cache {
+            clist: Threads::new(program.insts.len(), program.slots()),
+            nlist: Threads::new(program.insts.len(), program.slots()),
+            stack: Vec::new(),
+            caps: vec![None; program.slots()],
+            backtracker: Backtracker::new(program),
+        }
+    }
+}
+
+impl From<&NFA> for Program {
+    fn from(nfa: &NFA) -> Program {
+        let index: BTreeMap<_, _> = nfa
+            .states()
+            .into_iter()
+            .enumerate()
+            .map(|(i, s)| (s, i as InstPtr))
+            .collect();
+        let mut insts = vec![Inst::Fail; index.len()];
+
+        for (&state, &pc) in &index {
+            let mut branches = Vec::new();
+            let mut characters = Vec::new();
+            let mut ranges = Vec::new();
+            for (&(_, t), ns) in nfa.outgoing(state) {
+                for n in ns {
+                    match t {
+                        Epsilon => branches.push(Inst::Jump(index[n])),
+                        Character(c) => characters.push((c, index[n])),
+                        Range(lo, hi) => ranges.push(Inst::Range(lo, hi, index[n])),
+                        Assert(assertion) => branches.push(Inst::Assert(assertion, index[n])),
+                        Transition::Look(look) => branches.push(Inst::Look(look, index[n])),
+                        Save(slot) => branches.push(Inst::Save(slot, index[n])),
+                        Backref(group, caseless) => {
+                            branches.push(Inst::Backref(group, caseless, index[n]))
+                        }
+                    }
+                }
+            }
+
+            for (lo, hi, next) in Program::ranges(characters) {
+                if lo == hi {
+                    branches.push(Inst::Char(lo, next));
+                } else {
+                    branches.push(Inst::Range(lo, hi, next));
+                }
+            }
+            branches.extend(ranges);
+            if nfa.accept.contains(&state) {
+                branches.push(Inst::Match);
+            }
+
+            insts[pc as usize] = match branches.len() {
+                0 => Inst::Fail,
+                1 => branches[0],
+                _ => {
+                    let mut pointers: Vec<InstPtr> = branches
+                        .into_iter()
+                        .map(|branch| match branch {
+                            Inst::Jump(next) => next,
+                            _ => {
+                                insts.push(branch);
+                                insts.len() as InstPtr - 1
+                            }
+                        })
+                        .collect();
+
+                    let mut rest = pointers.pop().unwrap();
+                    while pointers.len() > 1 {
+                        insts.push(Inst::Split(pointers.pop().unwrap(), rest));
+                        rest = insts.len() as InstPtr - 1;
+                    }
+                    Inst::Split(pointers[0], rest)
+                }
+            };
+        }
+
+        Program {
+            insts,
+            start: index[&nfa.start],
+            looks: nfa
+                .looks
+                .iter()
+                .map(|&(look, ref nfa)| (look, Program::from(nfa)))
+                .collect(),
+        }
+    }
+}
+
+impl Program {
+    /// Merges transitions on consecutive characters leading to the same instruction into
+    /// ranges. The order of targets is preserved.
+    fn ranges(mut characters: Vec<(char, InstPtr)>) -> Vec<(char, char, InstPtr)> {
+        let mut order = Vec::new();
+        for &(_, next) in &characters {
+            if !order.contains(&next) {
+                order.push(next);
+            }
+        }
+        characters.sort_by_key(|&(c, next)| (next, c));
+
+        let mut result: Vec<(char, char, InstPtr)> = Vec::new();
+        for (c, next) in characters {
+            if let Some(last) = result.last_mut() {
+                if last.2 == next && (last.1 as u32) + 1 == c as u32 {
+                    last.1 = c;
+                    continue;
+                }
+            }
+            result.push((c, c, next));
+        }
+        result.sort_by_key(|r| order.iter().position(|x| *x == r.2));
+
+        result
+    }
+
+    /// Adds `pc` and everything reachable from it without consuming input to `threads`, with
+    /// the capture slots in `caps`, which are only tracked as far as `caps` goes. Instructions
+    /// already there are skipped, and the rest are added in priority order.
+    fn add_thread(
+        &self,
+        threads: &mut Threads,
+        stack: &mut Vec<Frame>,
+        pc: InstPtr,
+        caps: &mut [Option<usize>],
+        at: At,
+    ) {
+        stack.push(Frame::Explore(pc));
+        while let Some(frame) = stack.pop() {
+            let pc = match frame {
+                Frame::Explore(pc) => pc,
+                Frame::Restore(slot, value) => {
+                    caps[slot] = value;
+                    continue;
+                }
+            };
+            if !threads.set.insert(pc) {
+                continue;
+            }
+            let from = pc as usize * threads.slots;
+            threads.caps[from..from + caps.len()].copy_from_slice(caps);
+
+            match self.insts[pc as usize] {
+                Inst::Jump(next) => stack.push(Frame::Explore(next)),
+                Inst::Split(x, y) => {
+                    stack.push(Frame::Explore(y));
+                    stack.push(Frame::Explore(x));
+                }
+                Inst::Assert(assertion, next) if assertion.holds(at.previous, at.next) => {
+                    stack.push(Frame::Explore(next))
+                }
+                Inst::Look(look, next) if at.looks[look as usize][at.pos] => {
+                    stack.push(Frame::Explore(next))
+                }
+                Inst::Save(slot, next) if (slot as usize) < caps.len() => {
+                    let slot = slot as usize;
+                    stack.push(Frame::Restore(slot, caps[slot]));
+                    caps[slot] = Some(at.offset);
+                    stack.push(Frame::Explore(next));
+                }
+                Inst::Save(_, next) => stack.push(Frame::Explore(next)),
+                Inst::Backref(..) => unreachable!("programs with backreferences are backtracked"),
+                _ => {}
+            }
+        }
+    }
+
+    /// Thompson simulation that keeps all of its state in `cache`, so that nothing is allocated
+    /// when the same cache is reused across calls.
+    pub fn run_with(&self, cache: &mut Cache, s: &str) -> bool {
+        if self.has_backrefs() {
+            return self.run_bounded_backtracking(s);
+        }
+
+        let Cache {
+            ref mut clist,
+            ref mut nlist,
+            ref mut stack,
+            ..
+        } = *cache;
+
+        let looks = self.look_tables_for(s);
+        let mut chars = s.chars().peekable();
+        let mut at = At {
+            previous: None,
+            next: chars.peek().cloned(),
+            pos: 0,
+            offset: 0,
+            looks: &looks,
+        };
+        clist.set.clear();
+        self.add_thread(clist, stack, self.start, &mut [], at);
+
+        while let Some(c) = chars.next() {
+            at.previous = Some(c);
+            at.next = chars.peek().cloned();
+            at.pos += 1;
+            at.offset += c.len_utf8();
+            nlist.set.clear();
+            for pc in clist.set.iter() {
+                if let Some(next) = self.insts[*pc as usize].step(c) {
+                    self.add_thread(nlist, stack, next, &mut [], at);
+                }
+            }
+            mem::swap(clist, nlist);
+
+            if clist.set.is_empty() {
+                return false;
+            }
+        }
+
+        clist
+            .set
+            .iter()
+            .any(|pc| self.insts[*pc as usize] == Inst::Match)
+    }
+
+    /// Searches for the leftmost-first match with the Pike VM, returning its byte offsets.
+    ///
+    /// Threads are kept in priority order, and each of them remembers where its match started.
+    /// A new thread is started at every position, with the lowest priority, until some match is
+    /// found. When a thread reaches `Match`, all threads of lower priority are cut off, so the
+    /// result is the same as what a backtracking engine would find first.
+    pub fn find(&self, s: &str) -> Option<(usize, usize)> {
+        self.find_with(&mut Cache::new(self), s)
+    }
+
+    pub fn find_with(&self, cache: &mut Cache, s: &str) -> Option<(usize, usize)> {
+        self.find_at(cache, s, 0)
+    }
+
+    /// Same as `find_with`, for a match starting at byte offset `start` or later, see
+    /// `captures_at`.
+    pub fn find_at(&self, cache: &mut Cache, s: &str, start: usize) -> Option<(usize, usize)> {
+        self.find_in(cache, &Haystack::new(self, s), start)
+    }
+
+    /// Same as `find_at`, in a haystack built for this program, which successive searches in
+    /// the same text can share.
+    pub fn find_in(
+        &self,
+        cache: &mut Cache,
+        haystack: &Haystack,
+        start: usize,
+    ) -> Option<(usize, usize)> {
+        if self.has_backrefs() {
+            return cache
+                .backtracker
+                .captures(self, haystack, start)
+                .map(|groups| groups[0].unwrap());
+        }
+
+        self.search(cache, haystack, start, 2)
+            .map(|caps| (caps[0].unwrap(), caps[1].unwrap()))
+    }
+
+    /// Same search as `find`, returning byte offsets of every group as well, with the whole
+    /// match as group 0. Groups that did not take part in the match are `None`.
+    ///
+    /// Every thread carries its own copy of the capture slots, and `Save` writes the current
+    /// position in the copy of the thread going through it. Threads are cut off in the same
+    /// way as in `find`, so groups end up where a backtracking engine would put them.
+    pub fn captures(&self, s: &str) -> Option<Vec<Option<(usize, usize)>>> {
+        self.captures_with(&mut Cache::new(self), s)
+    }
+
+    pub fn captures_with(&self, cache: &mut Cache, s: &str) -> Option<Vec<Option<(usize, usize)>>> {
+        self.captures_at(cache, s, 0)
+    }
+
+    /// Same as `captures_with`, for a match starting at byte offset `start` or later. The text
+    /// before `start` is still there for assertions and lookbehinds to look at.
+    pub fn captures_at(
+        &self,
+        cache: &mut Cache,
+        s: &str,
+        start: usize,
+    ) -> Option<Vec<Option<(usize, usize)>>> {
+        self.captures_in(cache, &Haystack::new(self, s), start)
+    }
+
+    /// Same as `captures_at`, in a haystack built for this program, see `find_in`.
+    pub fn captures_in(
+        &self,
+        cache: &mut Cache,
+        haystack: &Haystack,
+        start: usize,
+    ) -> Option<Vec<Option<(usize, usize)>>> {
+        if self.has_backrefs() {
+            return cache.backtracker.captures(self, haystack, start);
+        }
+
+        let caps = self.search(cache, haystack, start, self.slots())?;
+        Some(
+            caps.chunks(2)
+                .map(|slots| match (slots[0], slots[1]) {
+                    (Some(from), Some(to)) => Some((from, to)),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    /// The Pike VM behind `find` and `captures`, tracking the first `slots` capture slots. Slots
+    /// 0 and 1 are the bounds of the whole match, which starts at byte offset `start` or later.
+    fn search(
+        &self,
+        cache: &mut Cache,
+        haystack: &Haystack,
+        start: usize,
+        slots: usize,
+    ) -> Option<Vec<Option<usize>>> {
+        let Cache {
+            ref mut clist,
+            ref mut nlist,
+            ref mut stack,
+            ref mut caps,
+            ..
+        } = *cache;
+        let caps = &mut caps[..slots];
+
+        let Haystack {
+            ref chars,
+            ref offsets,
+            ref looks,
+            ..
+        } = *haystack;
+        let mut result = None;
+        let skipped = haystack.position(start);
+        let mut previous = skipped.checked_sub(1).map(|k| chars[k]);
+        clist.set.clear();
+
+        for k in skipped..offsets.len() {
+            let current = chars.get(k).cloned();
+            let pos = offsets[k];
+            if result.is_none() {
+                let at = At {
+                    previous,
+                    next: current,
+                    pos: k,
+                    offset: pos,
+                    looks,
+                };
+                for slot in caps.iter_mut() {
+                    *slot = None;
+                }
+                caps[0] = Some(pos);
+                self.add_thread(clist, stack, self.start, caps, at);
+            }
+
+            nlist.set.clear();
+            for pc in clist.set.iter() {
+                match (self.insts[*pc as usize], current) {
+                    (Inst::Match, _) => {
+                        let mut matched = clist.caps(*pc)[..slots].to_vec();
+                        matched[1] = Some(pos);
+                        result = Some(matched);
+                        break;
+                    }
+                    (ref inst, Some(c)) => {
+                        if let Some(next) = inst.step(c) {
+                            caps.copy_from_slice(&clist.caps(*pc)[..slots]);
+                            let at = At {
+                                previous: Some(c),
+                                next: chars.get(k + 1).cloned(),
+                                pos: k + 1,
+                                offset: offsets[k + 1],
+                                looks,
+                            };
+                            self.add_thread(nlist, stack, next, caps, at);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            mem::swap(clist, nlist);
+            previous = current;
+
+            if clist.set.is_empty() && result.is_some() {
+                break;
+            }
+        }
+
+        result
+    }
+
+    pub fn has_backrefs(&self) -> bool {
+        self.insts
+            .iter()
+            .any(|inst| matches!(*inst, Inst::Backref(..)))
+    }
+
+    /// Number of capture slots, two for every group, counting the whole match as group 0.
+    pub fn slots(&self) -> usize {
+        let groups = self
+            .insts
+            .iter()
+            .filter_map(|inst| match *inst {
+                Inst::Save(slot, _) => Some(slot / 2),
+                Inst::Backref(group, ..) => Some(group),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+
+        2 * (groups as usize + 1)
+    }
+
+    /// Computes where each lookaround holds in `chars`. Lookbehinds come from a forward scan that
+    /// starts a match of their program at every position, which is the same as running the
+    /// intersection of `.*` followed by the pattern with the input so far. Lookaheads come from a
+    /// backward scan, which runs the reversed program over the reversed input.
+    ///
+    /// Either way, this takes `O(instructions × input)` time for each lookaround.
+    pub fn look_tables(&self, chars: &[char]) -> LookTables {
+        self.looks
+            .iter()
+            .map(|&(look, ref program)| {
+                let mut table = if look.is_ahead() {
+                    program.match_starts(chars)
+                } else {
+                    program.match_ends(chars)
+                };
+                if look.is_negated() {
+                    for holds in &mut table {
+                        *holds = !*holds;
+                    }
+                }
+                table
+            })
+            .collect()
+    }
+
+    fn look_tables_for(&self, s: &str) -> LookTables {
+        if self.looks.is_empty() {
+            return Vec::new();
+        }
+
+        self.look_tables(&s.chars().collect::<Vec<_>>())
+    }
+
+    /// Positions where some match of the program ends.
+    fn match_ends(&self, chars: &[char]) -> Vec<bool> {
+        let looks = self.look_tables(chars);
+        let mut cache = Cache::new(self);
+        let Cache {
+            ref mut clist,
+            ref mut nlist,
+            ref mut stack,
+            ..
+        } = cache;
+
+        // nothing is captured here, so byte offsets don't matter
+        let mut result = vec![false; chars.len() + 1];
+        clist.set.clear();
+        for pos in 0..=chars.len() {
+            let at = At {
+                previous: pos.checked_sub(1).map(|i| chars[i]),
+                next: chars.get(pos).cloned(),
+                pos,
+                offset: 0,
+                looks: &looks,
+            };
+            self.add_thread(clist, stack, self.start, &mut [], at);
+            result[pos] = clist
+                .set
+                .iter()
+                .any(|pc| self.insts[*pc as usize] == Inst::Match);
+
+            if pos < chars.len() {
+                let at = At {
+                    previous: Some(chars[pos]),
+                    next: chars.get(pos + 1).cloned(),
+                    pos: pos + 1,
+                    offset: 0,
+                    looks: &looks,
+                };
+                nlist.set.clear();
+                for pc in clist.set.iter() {
+                    if let Some(next) = self.insts[*pc as usize].step(chars[pos]) {
+                        self.add_thread(nlist, stack, next, &mut [], at);
+                    }
+                }
+                mem::swap(clist, nlist);
+            }
+        }
+
+        result
+    }
+
+    /// Positions where some match of the program starts. Going from the end of the input to the
+    /// start, keeps the set of instructions from which `Match` can be reached by consuming the
+    /// rest of the input up to some position.
+    fn match_starts(&self, chars: &[char]) -> Vec<bool> {
+        let looks = self.look_tables(chars);
+
+        let mut predecessors = vec![Vec::new(); self.insts.len()];
+        for (pc, inst) in self.insts.iter().enumerate() {
+            match *inst {
+                Inst::Jump(next)
+                | Inst::Assert(_, next)
+                | Inst::Look(_, next)
+                | Inst::Save(_, next) => predecessors[next as usize].push(pc as InstPtr),
+                Inst::Split(x, y) => {
+                    predecessors[x as usize].push(pc as InstPtr);
+                    predecessors[y as usize].push(pc as InstPtr);
+                }
+                _ => {}
+            }
+        }
+
+        let mut result = vec![false; chars.len() + 1];
+        let mut live = vec![false; self.insts.len()];
+        let mut current = vec![false; self.insts.len()];
+        let mut stack = Vec::new();
+        for pos in (0..=chars.len()).rev() {
+            for (pc, inst) in self.insts.iter().enumerate() {
+                let seed = match *inst {
+                    Inst::Match => true,
+                    _ => {
+                        pos < chars.len() && inst.step(chars[pos]).is_some_and(|n| live[n as usize])
+                    }
+                };
+                current[pc] = seed;
+                if seed {
+                    stack.push(pc as InstPtr);
+                }
+            }
+
+            while let Some(pc) = stack.pop() {
+                for &p in &predecessors[pc as usize] {
+                    let passes = match self.insts[p as usize] {
+                        Inst::Assert(assertion, _) => assertion.holds_at(chars, pos),
+                        Inst::Look(look, _) => looks[look as usize][pos],
+                        _ => true,
+                    };
+                    if passes && !current[p as usize] {
+                        current[p as usize] = true;
+                        stack.push(p);
+                    }
+                }
+            }
+
+            result[pos] = current[self.start as usize];
+            mem::swap(&mut live, &mut current);
+        }
+
+        result
+    }
+
+    /// Same breadth-first search as `NFA::run_backtracking`, which can blow up on adversarial
+    /// inputs just as well.
+    pub fn run_backtracking(&self, s: &str) -> bool {
+        if self.has_backrefs() {
+            return self.run_bounded_backtracking(s);
+        }
+
+        let s: Vec<char> = s.chars().collect();
+        let looks = self.look_tables(&s);
+
+        let mut queue = VecDeque::new();
+        queue.push_back((self.start, 0));
+
+        while let Some((pc, pos)) = queue.pop_front() {
+            match self.insts[pc as usize] {
+                Inst::Match => {
+                    if pos == s.len() {
+                        return true;
+                    }
+                }
+                Inst::Fail => {}
+                Inst::Jump(next) | Inst::Save(_, next) => queue.push_back((next, pos)),
+                Inst::Split(x, y) => {
+                    queue.push_back((x, pos));
+                    queue.push_back((y, pos));
+                }
+                Inst::Backref(..) => unreachable!("programs with backreferences are backtracked"),
+                Inst::Assert(assertion, next) => {
+                    if assertion.holds_at(&s, pos) {
+                        queue.push_back((next, pos));
+                    }
+                }
+                Inst::Look(look, next) => {
+                    if looks[look as usize][pos] {
+                        queue.push_back((next, pos));
+                    }
+                }
+                ref inst => {
+                    if pos < s.len() {
+                        if let Some(next) = inst.step(s[pos]) {
+                            queue.push_back((next, pos + 1));
+                        }
+                    }
+                }
+            }
+        }
+        false
+    }
+}
+
+impl Automaton for Program {
+    fn run(&self, s: &str) -> bool {
+        self.run_with(&mut Cache::new(self), s)
+    }
+
+    fn write_graphviz(&self, filename: &str) -> Result<()> {
+        let path = Path::new(filename);
+        let mut file = File::create(path).chain_err(|| "unable to create file")?;
+
+        writeln!(&mut file, "digraph program {{").chain_err(|| "")?;
+        writeln!(&mut file, "\trankdir=LR;").chain_err(|| "")?;
+        write!(&mut file, "\tnode [shape = doublecircle]; ").chain_err(|| "")?;
+        for (pc, inst) in self.insts.iter().enumerate() {
+            if *inst == Inst::Match {
+                write!(&mut file, "{} ", pc).chain_err(|| "")?;
+            }
+        }
+        writeln!(&mut file, ";\n\tnode [shape = circle];").chain_err(|| "")?;
+        for (pc, inst) in self.insts.iter().enumerate() {
+            let edges = match *inst {
+                Inst::Match | Inst::Fail => vec![],
+                Inst::Char(c, next) => vec![(next, c.to_string())],
+                Inst::Range(lo, hi, next) => vec![(next, format!("{}-{}", lo, hi))],
+                Inst::Split(x, y) => vec![(x, "ε1".to_string()), (y, "ε2".to_string())],
+                Inst::Jump(next) => vec![(next, "ε".to_string())],
+                Inst::Assert(assertion, next) => vec![(next, assertion.to_string())],
+                Inst::Look(look, next) => {
+                    vec![(next, format!("{}#{}", self.looks[look as usize].0, look))]
+                }
+                Inst::Save(slot, next) => vec![(next, format!("save {}", slot))],
+                Inst::Backref(group, false, next) => vec![(next, format!("\\{}", group))],
+                Inst::Backref(group, true, next) => vec![(next, format!("(?i:\\{})", group))],
+            };
+
+            for (next, label) in edges {
+                writeln!(&mut file, "\t{} -> {} [ label = \"{}\"]", pc, next, label)
+                    .chain_err(|| "")?;
+            }
+        }
+
+        writeln!(&mut file, "}}").chain_err(|| "")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Compiler, Construction, Flags, AST};
+
+    /// Checks that the Pike VM and backtracking both find `span` in `input`.
+    fn assert_find(pattern: &str, input: &str, span: Option<(usize, usize)>) {
+        assert_find_with(pattern, Flags::default(), input, span);
+    }
+
+    fn assert_find_with(pattern: &str, flags: Flags, input: &str, span: Option<(usize, usize)>) {
+        let program = AST::parse_with(pattern, flags).unwrap().into_program();
+
+        assert_eq!(program.find(input), span, "{}", pattern);
+        assert_eq!(program.find_backtracking(input), span, "{}", pattern);
+    }
+
+    #[test]
+    fn literal() {
+        let program = Program::from(&AST::literal('a').into_nfa().unwrap());
+
+        assert_eq!(
+            program,
+            Program {
+                insts: vec![Inst::Char('a', 1), Inst::Match],
+                start: 0,
+                looks: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn split() {
+        let program = Program::from(&AST::star(AST::literal('a')).into_nfa().unwrap());
+
+        assert_eq!(
+            program,
+            Program {
+                insts: vec![
+                    Inst::Split(1, 3),
+                    Inst::Char('a', 2),
+                    Inst::Split(1, 3),
+                    Inst::Match,
+                ],
+                start: 0,
+                looks: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn ranges() {
+        let nfa = NFA::new(
+            0,
+            btreeset!(1),
+            btreemap!(
+                (0, Character('a')) => btreeset!(1),
+                (0, Character('b')) => btreeset!(1),
+                (0, Character('c')) => btreeset!(1),
+                (0, Character('x')) => btreeset!(1),
+            ),
+        );
+
+        assert_eq!(
+            Program::from(&nfa),
+            Program {
+                insts: vec![
+                    Inst::Split(2, 3),
+                    Inst::Match,
+                    Inst::Range('a', 'c', 1),
+                    Inst::Char('x', 1),
+                ],
+                start: 0,
+                looks: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn run() {
+        let ast = AST::parse("(?:a|b)*abb").unwrap();
+        let thompson = Program::from(&ast.clone().into_nfa().unwrap());
+        let glushkov = Program::from(
+            &Compiler::new()
+                .construction(Construction::Glushkov)
+                .compile(ast)
+                .unwrap(),
+        );
+
+        for program in &[thompson, glushkov] {
+            assert!(program.run("abb"));
+            assert!(program.run("babb"));
+            assert!(!program.run("ab"));
+            assert!(!program.run("abbc"));
+
+            assert!(program.run_backtracking("abb"));
+            assert!(program.run_backtracking("babb"));
+            assert!(!program.run_backtracking("ab"));
+            assert!(!program.run_backtracking("abbc"));
+        }
+    }
+
+    #[test]
+    fn cache_reuse() {
+        let program = Program::from(&AST::parse("(?:a|b)*abb").unwrap().into_nfa().unwrap());
+        let mut cache = Cache::new(&program);
+
+        assert!(program.run_with(&mut cache, "abb"));
+        assert!(!program.run_with(&mut cache, "abba"));
+        assert!(program.run_with(&mut cache, "aabb"));
+    }
+
+    #[test]
+    fn find() {
+        let program = AST::parse("a+").unwrap().into_program();
+
+        assert_eq!(program.find("baaab"), Some((1, 4)));
+        assert_eq!(program.find("bbb"), None);
+        assert_eq!(program.find("ä"), None);
+        assert_eq!(program.find("äa"), Some((2, 3)));
+    }
+
+    #[test]
+    fn find_leftmost_first() {
+        let program = AST::parse("a|ab").unwrap().into_program();
+        assert_eq!(program.find("ab"), Some((0, 1)));
+
+        let program = AST::parse("(a|ab)(c|bcd)").unwrap().into_program();
+        assert_eq!(program.find("abcd"), Some((0, 4)));
+    }
+
+    #[test]
+    fn lazy() {
+        assert_find("a*?", "aaa", Some((0, 0)));
+        assert_find("a+?", "aaa", Some((0, 1)));
+        assert_find("a??", "a", Some((0, 0)));
+        assert_find("a*", "aaa", Some((0, 3)));
+        assert_find("\"(.*?)\"", "\"a\" \"b\"", Some((0, 3)));
+        assert_find("\"(.*)\"", "\"a\" \"b\"", Some((0, 7)));
+        assert_find("a.*?c", "abcbc", Some((0, 3)));
+    }
+
+    #[test]
+    fn captures() {
+        for &(pattern, input) in &[
+            ("(a+)(b)?(c)", "äaac"),
+            ("(a|ab)(c|bcd)(d*)", "abcd"),
+            ("(a*?)(a*)", "aaa"),
+            ("((a)|b)+", "ab"),
+            ("(?:(a)|b)*c", "abc"),
+            ("x(?=(y))", "xy"),
+        ] {
+            let program = AST::parse(pattern).unwrap().into_program();
+
+            assert_eq!(
+                program.captures(input),
+                program.captures_backtracking(input),
+                "{}",
+                pattern
+            );
+        }
+
+        let program = AST::parse("(a)|(b)").unwrap().into_program();
+        assert_eq!(
+            program.captures("xb"),
+            Some(vec![Some((1, 2)), None, Some((1, 2))])
+        );
+    }
+
+    #[test]
+    fn captures_at() {
+        let program = AST::parse("(?<=é)(\\w)").unwrap().into_program();
+        let mut cache = Cache::new(&program);
+        for &start in &[0, 2, 3, 4, 6, 7] {
+            assert_eq!(
+                program.captures_at(&mut cache, "éa éb", start),
+                program.captures_backtracking_at("éa éb", start),
+            );
+        }
+        assert_eq!(
+            program.captures_at(&mut cache, "éa éb", 2),
+            Some(vec![Some((2, 3)), Some((2, 3))])
+        );
+        assert_eq!(
+            program.captures_at(&mut cache, "éa éb", 3),
+            Some(vec![Some((6, 7)), Some((6, 7))])
+        );
+        assert_eq!(program.captures_at(&mut cache, "éa éb", 7), None);
+
+        // the start of the search is not a word boundary
+        let program = AST::parse("\\b\\w").unwrap().into_program();
+        assert_eq!(
+            program.captures_at(&mut Cache::new(&program), "éa éb", 2),
+            Some(vec![Some((4, 6))])
+        );
+        assert_eq!(
+            program.captures_backtracking_at("éa éb", 2),
+            Some(vec![Some((4, 6))])
+        );
+    }
+
+    #[test]
+    fn anchors() {
+        let multi_line = Flags {
+            multi_line: true,
+            ..Flags::default()
+        };
+        assert_find_with("^a", Flags::default(), "aa", Some((0, 1)));
+        assert_find_with("a$", Flags::default(), "aa", Some((1, 2)));
+        assert_find_with("^b", Flags::default(), "a\nb", None);
+        assert_find_with("^b", multi_line, "a\nb", Some((2, 3)));
+        assert_find_with("a$", multi_line, "a\nb", Some((0, 1)));
+        assert_find_with("\\Ab", multi_line, "a\nb", None);
+        assert_find_with("a\\z", multi_line, "a\nb", None);
+        assert_find_with("^$", multi_line, "a\n\nb", Some((2, 2)));
+        assert_find_with("$", Flags::default(), "äb", Some((3, 3)));
+
+        let program = AST::parse("^a*$").unwrap().into_program();
+        assert!(program.run("aa"));
+        assert!(program.run_backtracking("aa"));
+        assert!(program.run_bounded_backtracking("aa"));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        for &(pattern, input, span) in &[
+            ("(?i)abc", "xAbC", Some((1, 4))),
+            ("(?i)[a-c]+", "xCaB", Some((1, 4))),
+            ("(?i:a)b", "AB Ab", Some((3, 5))),
+            ("(?i)\u{212A}", "xK", Some((1, 2))),
+            ("(?i)ä", "Ä", Some((0, 2))),
+            ("(?i)[^a]", "Aa", None),
+            ("(?s).", "\n", Some((0, 1))),
+            ("(?x) a b # c", "ab c", Some((0, 2))),
+        ] {
+            assert_find(pattern, input, span);
+            assert_eq!(
+                AST::parse(pattern)
+                    .unwrap()
+                    .to_dfa_by_derivatives()
+                    .unwrap()
+                    .run(&input[span.map_or(0, |s| s.0)..]),
+                span.is_some_and(|s| s.1 == input.len()),
+                "{}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn unicode_classes() {
+        assert_find("\\p{Greek}+", "abc αβγ", Some((4, 10)));
+        assert_find("\\w+", "-été_1-", Some((1, 8)));
+        assert_find("(?a)\\w+", "-é_1-", Some((3, 5)));
+        assert_find("\\d+", "x١٢3", Some((1, 6)));
+        assert_find("\\S+", " \u{3000}ab", Some((4, 6)));
+        assert_find("[^\\p{L}\\s]", "a b!", Some((3, 4)));
+    }
+
+    #[test]
+    fn word_boundaries() {
+        assert_find("\\bis\\b", "this is", Some((5, 7)));
+        assert_find("\\Bis\\B", "this is", None);
+        assert_find("\\b", "  ", None);
+        assert_find("\\b.+\\b", "-ab-", Some((1, 3)));
+    }
+
+    #[test]
+    fn lookarounds() {
+        assert_find("a(?=b)", "acab", Some((2, 3)));
+        assert_find("a(?!b)", "abac", Some((2, 3)));
+        assert_find("(?<=a)b", "cbab", Some((3, 4)));
+        assert_find("(?<!a)b", "abcb", Some((3, 4)));
+        assert_find("(?=a*b)a+", "aac aab", Some((4, 6)));
+        assert_find("(?=.*c)a", "abc", Some((0, 1)));
+        assert_find("(?<=(?<!x)a)b", "xabab", Some((4, 5)));
+        assert_find("(?<=a(?=b))b", "ab", Some((1, 2)));
+        assert_find("(?<=ä)b", "äb", Some((2, 3)));
+        assert_find("(?!a)", "aa", Some((2, 2)));
+
+        let nfa = AST::parse("(?=a).*(?<!c)").unwrap().into_nfa().unwrap();
+        let program = nfa.to_program();
+        for &(input, matches) in &[("abc", false), ("ab", true), ("bc", false), ("", false)] {
+            assert_eq!(nfa.run(input), matches, "{}", input);
+            assert_eq!(nfa.run_backtracking(input), matches, "{}", input);
+            assert_eq!(program.run(input), matches, "{}", input);
+            assert_eq!(program.run_backtracking(input), matches, "{}", input);
+            assert_eq!(
+                program.run_bounded_backtracking(input),
+                matches,
+                "{}",
+                input
+            );
+        }
+        assert!(nfa.to_dfa().is_err());
+    }
+
+    #[test]
+    fn empty() {
+        let program = Program::from(&AST::empty().into_nfa().unwrap());
+
+        assert!(!program.run(""));
+        assert!(!program.run_backtracking(""));
+    }
+}
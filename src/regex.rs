@@ -0,0 +1,948 @@
+use ast::Token::{Class, Group};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use {
+    AhoCorasick, Cache, CharClass, Flags, Haystack, LazyCache, LazyDFA, Prefilter, Program,
+    Transition, AST, DFA, NFA,
+};
+
+use errors::*;
+
+/// NFAs with more transitions than this are not turned into a DFA up front, as the subset
+/// construction could take exponential time. Every range of a class is a transition, so this
+/// also keeps out large Unicode classes, which make every DFA state slow to build. Assertions
+/// count for the ranges of characters they tell apart, like `\b` for `\w`.
+const DFA_NFA_TRANSITIONS: usize = 64;
+
+/// A compiled pattern, which picks an engine for every query:
+///
+/// * `is_match` runs a DFA of `(?s:.*)pattern(?s:.*)` built up front when the pattern is small
+///   and the DFA fits in its size limit, and a lazy DFA otherwise.
+/// * `find` and `captures` run the Pike VM, after `is_match` has made sure there is a match.
+/// * Lookarounds are beyond both DFAs, so the Pike VM does everything for patterns using them.
+/// * Backreferences are beyond all automata, so patterns using them are searched by
+///   backtracking, which can take exponential time. Under `(?i)` they match their group in
+///   any case.
+/// * Alternations of literal strings, like `if|else|while`, need none of these: an
+///   Aho–Corasick automaton does everything for them.
+///
+/// Before any of them, a `Prefilter` looks for the literal strings every match contains, so
+/// texts without them are rejected right away, and searches for a pattern starting with a
+/// literal jump from one occurrence of it to the next.
+pub struct Regex {
+    pattern: String,
+    program: Program,
+    dfa: Option<DFA>,
+    /// The lazy DFA, with the states it built in earlier queries.
+    lazy: Option<(LazyDFA, Mutex<LazyCache>)>,
+    prefilter: Option<Prefilter>,
+    ahocorasick: Option<AhoCorasick>,
+    /// Number of groups, not counting the whole match.
+    groups: usize,
+    names: BTreeMap<String, usize>,
+}
+
+/// Options for compiling a `Regex`, which can also be set inside the pattern, and limits on
+/// the size of its automata.
+#[derive(Debug, Clone)]
+pub struct RegexBuilder {
+    pattern: String,
+    flags: Flags,
+    nfa_size_limit: usize,
+    dfa_size_limit: usize,
+    cache_size: usize,
+}
+
+/// Groups of a match, found by `Regex::captures`. Group 0 is the whole match.
+#[derive(Debug)]
+pub struct Captures<'r, 't> {
+    regex: &'r Regex,
+    text: &'t str,
+    groups: Vec<Option<(usize, usize)>>,
+}
+
+/// Where the next search of an iterator over matches starts.
+#[derive(Debug)]
+struct Cursor {
+    /// Byte offset, past the end of the text once there is nothing more to find.
+    start: usize,
+    last_end: Option<usize>,
+}
+
+/// Iterator over successive non-overlapping matches, see `Regex::find_iter`.
+#[derive(Debug)]
+pub struct Matches<'r, 't> {
+    regex: &'r Regex,
+    haystack: Haystack<'t>,
+    cache: Cache,
+    cursor: Cursor,
+}
+
+/// Iterator over successive non-overlapping matches with their groups, see
+/// `Regex::captures_iter`.
+#[derive(Debug)]
+pub struct CaptureMatches<'r, 't> {
+    regex: &'r Regex,
+    haystack: Haystack<'t>,
+    cache: Cache,
+    cursor: Cursor,
+}
+
+/// Iterator over the substrings between matches, see `Regex::split`.
+#[derive(Debug)]
+pub struct Split<'r, 't> {
+    matches: Matches<'r, 't>,
+    /// Byte offset of the next substring, past the end of the text once they are all done.
+    last: usize,
+}
+
+/// Iterator over at most `n` substrings between matches, see `Regex::splitn`.
+#[derive(Debug)]
+pub struct SplitN<'r, 't> {
+    split: Split<'r, 't>,
+    n: usize,
+}
+
+/// How `Regex::replace` and friends turn every match into its replacement.
+pub trait Replacer {
+    /// Appends the replacement for `caps` to `dst`.
+    fn replace_append(&mut self, caps: &Captures<'_, '_>, dst: &mut String);
+}
+
+/// A replacement string taken as it is, without expanding `$` references.
+#[derive(Debug, Clone, Copy)]
+pub struct NoExpand<'s>(pub &'s str);
+
+impl RegexBuilder {
+    pub fn new(pattern: &str) -> RegexBuilder {
+        RegexBuilder {
+            pattern: pattern.to_string(),
+            flags: Flags::default(),
+            nfa_size_limit: 100_000,
+            dfa_size_limit: 10_000,
+            cache_size: 10_000,
+        }
+    }
+
+    /// Same as `(?i)`.
+    pub fn case_insensitive(mut self, yes: bool) -> RegexBuilder {
+        self.flags.case_insensitive = yes;
+        self
+    }
+
+    /// Same as `(?m)`.
+    pub fn multi_line(mut self, yes: bool) -> RegexBuilder {
+        self.flags.multi_line = yes;
+        self
+    }
+
+    /// Same as `(?s)`.
+    pub fn dot_matches_new_line(mut self, yes: bool) -> RegexBuilder {
+        self.flags.dot_matches_new_line = yes;
+        self
+    }
+
+    /// Turning Unicode off is the same as `(?a)`.
+    pub fn unicode(mut self, yes: bool) -> RegexBuilder {
+        self.flags.ascii_only = !yes;
+        self
+    }
+
+    /// Largest number of instructions of the compiled program, counting those of lookarounds.
+    /// `build` fails with `ErrorKind::SizeLimit` beyond that.
+    pub fn nfa_size_limit(mut self, states: usize) -> RegexBuilder {
+        self.nfa_size_limit = states;
+        self
+    }
+
+    /// Largest number of states of the DFA built up front. Beyond that, the lazy DFA is used.
+    pub fn dfa_size_limit(mut self, states: usize) -> RegexBuilder {
+        self.dfa_size_limit = states;
+        self
+    }
+
+    /// Largest number of states the lazy DFA keeps, see `LazyDFA::with_cache_size`.
+    pub fn cache_size(mut self, states: usize) -> RegexBuilder {
+        self.cache_size = states;
+        self
+    }
+
+    pub fn build(&self) -> Result<Regex> {
+        let ast = AST::parse_with(&self.pattern, self.flags)?;
+        let mut names = BTreeMap::new();
+        let groups = Regex::groups(&ast, &mut names);
+
+        let prefilter = Prefilter::new(ast.literals());
+        let nfa = match ast.clone().into_nfa() {
+            Ok(nfa) => Some(nfa),
+            // patterns with backreferences are only compiled to programs, for backtracking
+            Err(Error(ErrorKind::UnsupportedFeature(..), _)) => None,
+            Err(e) => return Err(e),
+        };
+        let program = match nfa {
+            Some(ref nfa) => nfa.to_program(),
+            None => ast.clone().into_program(),
+        };
+        if RegexBuilder::instructions(&program) > self.nfa_size_limit {
+            bail!(ErrorKind::SizeLimit("NFA".to_string(), self.nfa_size_limit));
+        }
+        let ahocorasick = AhoCorasick::from_ast(&ast);
+        let mut dfa = None;
+        let mut lazy = None;
+        if let Some(ref nfa) = nfa {
+            if ahocorasick.is_none() && nfa.looks.is_empty() {
+                if RegexBuilder::ranges(nfa) <= DFA_NFA_TRANSITIONS {
+                    let any = AST::star(AST::new(Class(CharClass::any()), None));
+                    let unanchored = AST::concat(vec![any.clone(), ast, any]).into_nfa()?;
+                    dfa = match unanchored.to_dfa_with_limit(self.dfa_size_limit) {
+                        Ok(dfa) => Some(dfa),
+                        Err(Error(ErrorKind::SizeLimit(..), _)) => None,
+                        Err(e) => return Err(e),
+                    };
+                }
+                if dfa.is_none() {
+                    let dfa = LazyDFA::new(program.clone())?.with_cache_size(self.cache_size);
+                    let cache = Mutex::new(LazyCache::new(&dfa));
+                    lazy = Some((dfa, cache));
+                }
+            }
+        }
+
+        Ok(Regex {
+            pattern: self.pattern.clone(),
+            program,
+            dfa,
+            lazy,
+            prefilter,
+            ahocorasick,
+            groups,
+            names,
+        })
+    }
+
+    /// Transitions of `nfa`, counting those of assertions as the ranges they look at.
+    fn ranges(nfa: &NFA) -> usize {
+        nfa.transitions
+            .keys()
+            .map(|(_, transition)| match *transition {
+                Transition::Assert(assertion) => assertion.looks_at().ranges().len().max(1),
+                _ => 1,
+            })
+            .sum()
+    }
+
+    fn instructions(program: &Program) -> usize {
+        program.insts.len()
+            + program
+                .looks
+                .iter()
+                .map(|(_, program)| RegexBuilder::instructions(program))
+                .sum::<usize>()
+    }
+}
+
+impl Regex {
+    /// Compiles `pattern` with the default options of `RegexBuilder`.
+    pub fn new(pattern: &str) -> Result<Regex> {
+        RegexBuilder::new(pattern).build()
+    }
+
+    /// Counts groups of `ast` and collects their names.
+    fn groups(ast: &AST, names: &mut BTreeMap<String, usize>) -> usize {
+        let mut count = 0;
+        if let Group(index, ref name) = *ast.token() {
+            count = index as usize;
+            if let Some(ref name) = *name {
+                names.insert(name.clone(), index as usize);
+            }
+        }
+
+        ast.children()
+            .iter()
+            .map(|x| Regex::groups(x, names))
+            .fold(count, usize::max)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// First byte offset of `s`, from `start` on, where the prefilter says a match can start.
+    fn skip(&self, s: &str, start: usize) -> Option<usize> {
+        match self.prefilter {
+            Some(ref prefilter) => prefilter.skip(s, start),
+            None => Some(start),
+        }
+    }
+
+    /// Whether the prefilter or one of the automata can tell that nothing matches.
+    fn rejects(&self, s: &str) -> bool {
+        let start = match self.skip(s, 0) {
+            Some(start) => start,
+            None => return true,
+        };
+        if let Some(ref prefilter) = self.prefilter {
+            if prefilter.rejects(&s[start..]) {
+                return true;
+            }
+        }
+
+        if let Some(ref dfa) = self.dfa {
+            !dfa.run_from(s, start)
+        } else if let Some((ref lazy, ref cache)) = self.lazy {
+            // while another thread has the cache, this query builds its own states
+            match cache.try_lock() {
+                Ok(mut cache) => !lazy.is_match_with(&mut cache, s),
+                Err(_) => !lazy.is_match(s),
+            }
+        } else if let Some(ref ahocorasick) = self.ahocorasick {
+            !ahocorasick.is_match(&s[start..])
+        } else {
+            false
+        }
+    }
+
+    /// Checks if some substring of `s` matches.
+    pub fn is_match(&self, s: &str) -> bool {
+        if self.dfa.is_some() || self.lazy.is_some() {
+            !self.rejects(s)
+        } else {
+            self.find(s).is_some()
+        }
+    }
+
+    /// Byte offsets of the leftmost-first match, the one a backtracking engine would find.
+    pub fn find(&self, s: &str) -> Option<(usize, usize)> {
+        if self.rejects(s) {
+            None
+        } else {
+            let haystack = Haystack::new(&self.program, s);
+            self.find_at(&mut Cache::new(&self.program), &haystack, 0)
+        }
+    }
+
+    /// Same match as `find`, with the bounds of every group.
+    pub fn captures<'t>(&self, s: &'t str) -> Option<Captures<'_, 't>> {
+        if self.rejects(s) {
+            None
+        } else {
+            let haystack = Haystack::new(&self.program, s);
+            self.captures_at(&mut Cache::new(&self.program), &haystack, 0)
+        }
+    }
+
+    /// Same as `captures`, for a match starting at byte offset `start` or later.
+    fn captures_at<'t>(
+        &self,
+        cache: &mut Cache,
+        haystack: &Haystack<'t>,
+        start: usize,
+    ) -> Option<Captures<'_, 't>> {
+        let s = haystack.text;
+        let start = self.skip(s, start)?;
+        let mut groups = if let Some(ref ahocorasick) = self.ahocorasick {
+            vec![Some(ahocorasick.find_at(s, start)?)]
+        } else {
+            self.program.captures_in(cache, haystack, start)?
+        };
+        // groups only used in lookarounds never make it to the program
+        groups.resize(self.groups + 1, None);
+
+        Some(Captures {
+            regex: self,
+            text: s,
+            groups,
+        })
+    }
+
+    /// Same as `find`, for a match starting at byte offset `start` or later.
+    fn find_at(
+        &self,
+        cache: &mut Cache,
+        haystack: &Haystack,
+        start: usize,
+    ) -> Option<(usize, usize)> {
+        let start = self.skip(haystack.text, start)?;
+        if let Some(ref ahocorasick) = self.ahocorasick {
+            ahocorasick.find_at(haystack.text, start)
+        } else {
+            self.program.find_in(cache, haystack, start)
+        }
+    }
+
+    /// Starts iterating over `s`, unless one of the DFAs can tell there is nothing to find.
+    fn cursor(&self, s: &str) -> Cursor {
+        Cursor {
+            start: if self.rejects(s) { s.len() + 1 } else { 0 },
+            last_end: None,
+        }
+    }
+
+    /// Successive non-overlapping matches of `s`, from left to right, each of them found like
+    /// `find` would from the end of the previous one. An empty match right where the previous
+    /// match ended is skipped.
+    pub fn find_iter<'r, 't>(&'r self, s: &'t str) -> Matches<'r, 't> {
+        Matches {
+            regex: self,
+            haystack: Haystack::new(&self.program, s),
+            cache: Cache::new(&self.program),
+            cursor: self.cursor(s),
+        }
+    }
+
+    /// Same matches as `find_iter`, with the bounds of every group.
+    pub fn captures_iter<'r, 't>(&'r self, s: &'t str) -> CaptureMatches<'r, 't> {
+        CaptureMatches {
+            regex: self,
+            haystack: Haystack::new(&self.program, s),
+            cache: Cache::new(&self.program),
+            cursor: self.cursor(s),
+        }
+    }
+
+    /// Substrings of `s` between the matches of `find_iter`, including those before the first
+    /// match and after the last one, which are empty when a match touches the end of `s`.
+    pub fn split<'r, 't>(&'r self, s: &'t str) -> Split<'r, 't> {
+        Split {
+            matches: self.find_iter(s),
+            last: 0,
+        }
+    }
+
+    /// Same as `split`, stopping after `n` substrings: the last one is the rest of `s`,
+    /// matches included.
+    pub fn splitn<'r, 't>(&'r self, s: &'t str, n: usize) -> SplitN<'r, 't> {
+        SplitN {
+            split: self.split(s),
+            n,
+        }
+    }
+
+    /// Replaces the leftmost-first match of `s`, see `replacen`.
+    pub fn replace<'t, R: Replacer>(&self, s: &'t str, replacer: R) -> Cow<'t, str> {
+        self.replacen(s, 1, replacer)
+    }
+
+    /// Replaces all the matches of `s`, see `replacen`.
+    pub fn replace_all<'t, R: Replacer>(&self, s: &'t str, replacer: R) -> Cow<'t, str> {
+        self.replacen(s, 0, replacer)
+    }
+
+    /// Replaces the first `limit` matches of `s` found by `captures_iter`, or all of them if
+    /// `limit` is 0. The replacement can be a string, where `$1`, `${1}`, `$name` and `${name}`
+    /// stand for groups as in `Captures::expand`, a `NoExpand` string, or a closure computing
+    /// it from the `Captures` of the match. `s` is borrowed back if nothing matches.
+    pub fn replacen<'t, R: Replacer>(
+        &self,
+        s: &'t str,
+        limit: usize,
+        mut replacer: R,
+    ) -> Cow<'t, str> {
+        let mut result = String::new();
+        let mut last = 0;
+        for (n, caps) in self.captures_iter(s).enumerate() {
+            if limit > 0 && n >= limit {
+                break;
+            }
+            let (from, to) = caps.pos(0).unwrap();
+            result.push_str(&s[last..from]);
+            replacer.replace_append(&caps, &mut result);
+            last = to;
+        }
+
+        if result.is_empty() && last == 0 {
+            return Cow::Borrowed(s);
+        }
+        result.push_str(&s[last..]);
+        Cow::Owned(result)
+    }
+}
+
+impl Cursor {
+    fn done(&self, text: &str) -> bool {
+        self.start > text.len()
+    }
+
+    /// Moves past the match `(from, to)`, and tells whether it is one to yield, which is not
+    /// the case of an empty match right at the end of the previous one.
+    fn advance(&mut self, text: &str, (from, to): (usize, usize)) -> bool {
+        if from == to {
+            let next = text[to..].chars().next();
+            self.start = to + next.map_or(1, char::len_utf8);
+            if self.last_end == Some(to) {
+                return false;
+            }
+        } else {
+            self.start = to;
+        }
+        self.last_end = Some(to);
+        true
+    }
+}
+
+impl<'r, 't> Iterator for Matches<'r, 't> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        let text = self.haystack.text;
+        while !self.cursor.done(text) {
+            let m = self
+                .regex
+                .find_at(&mut self.cache, &self.haystack, self.cursor.start);
+            match m {
+                Some(m) if self.cursor.advance(text, m) => return Some(m),
+                Some(_) => {}
+                None => self.cursor.start = text.len() + 1,
+            }
+        }
+
+        None
+    }
+}
+
+impl<'r, 't> Iterator for CaptureMatches<'r, 't> {
+    type Item = Captures<'r, 't>;
+
+    fn next(&mut self) -> Option<Captures<'r, 't>> {
+        let text = self.haystack.text;
+        while !self.cursor.done(text) {
+            let caps = self
+                .regex
+                .captures_at(&mut self.cache, &self.haystack, self.cursor.start);
+            match caps {
+                Some(caps) => {
+                    if self.cursor.advance(text, caps.pos(0).unwrap()) {
+                        return Some(caps);
+                    }
+                }
+                None => self.cursor.start = text.len() + 1,
+            }
+        }
+
+        None
+    }
+}
+
+impl<'r, 't> Split<'r, 't> {
+    /// Everything after the last match so far.
+    fn rest(&mut self) -> Option<&'t str> {
+        let text = self.matches.haystack.text;
+        if self.last > text.len() {
+            return None;
+        }
+
+        let rest = &text[self.last..];
+        self.last = text.len() + 1;
+        Some(rest)
+    }
+}
+
+impl<'r, 't> Iterator for Split<'r, 't> {
+    type Item = &'t str;
+
+    fn next(&mut self) -> Option<&'t str> {
+        match self.matches.next() {
+            Some((from, to)) => {
+                let piece = &self.matches.haystack.text[self.last..from];
+                self.last = to;
+                Some(piece)
+            }
+            None => self.rest(),
+        }
+    }
+}
+
+impl<'r, 't> Iterator for SplitN<'r, 't> {
+    type Item = &'t str;
+
+    fn next(&mut self) -> Option<&'t str> {
+        match self.n {
+            0 => None,
+            1 => {
+                self.n = 0;
+                self.split.rest()
+            }
+            _ => {
+                self.n -= 1;
+                self.split.next()
+            }
+        }
+    }
+}
+
+impl Replacer for &str {
+    fn replace_append(&mut self, caps: &Captures<'_, '_>, dst: &mut String) {
+        caps.expand(self, dst);
+    }
+}
+
+impl Replacer for &String {
+    fn replace_append(&mut self, caps: &Captures<'_, '_>, dst: &mut String) {
+        caps.expand(self, dst);
+    }
+}
+
+impl<'s> Replacer for NoExpand<'s> {
+    fn replace_append(&mut self, _: &Captures<'_, '_>, dst: &mut String) {
+        dst.push_str(self.0);
+    }
+}
+
+impl<F> Replacer for F
+where
+    F: FnMut(&Captures<'_, '_>) -> String,
+{
+    fn replace_append(&mut self, caps: &Captures<'_, '_>, dst: &mut String) {
+        dst.push_str(&self(caps));
+    }
+}
+
+impl fmt::Display for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+impl fmt::Debug for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Regex").field(&self.pattern).finish()
+    }
+}
+
+impl<'r, 't> Captures<'r, 't> {
+    /// Byte offsets of a group, if it took part in the match.
+    pub fn pos(&self, group: usize) -> Option<(usize, usize)> {
+        self.groups.get(group).cloned().and_then(|x| x)
+    }
+
+    /// Text matched by a group, if it took part in the match.
+    pub fn get(&self, group: usize) -> Option<&'t str> {
+        self.pos(group).map(|(from, to)| &self.text[from..to])
+    }
+
+    /// Text matched by a named group, if it took part in the match.
+    pub fn name(&self, name: &str) -> Option<&'t str> {
+        self.regex
+            .names
+            .get(name)
+            .and_then(|&group| self.get(group))
+    }
+
+    /// Number of groups, including the whole match.
+    pub fn count(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Appends `replacement` to `dst`, with `$1` or `${1}` replaced by the text of group 1, and
+    /// `$name` or `${name}` by the text of the group with that name. Groups that did not take
+    /// part in the match, or don't exist, are replaced by nothing, and `$$` is a single `$`.
+    ///
+    /// Without braces, the name is as long as possible: `$1a` refers to a group named `1a`,
+    /// which is probably not what was meant, so `${1}a` is the way to write that. A `$`
+    /// followed by anything else is kept as it is.
+    pub fn expand(&self, replacement: &str, dst: &mut String) {
+        let mut rest = replacement;
+        while let Some(i) = rest.find('$') {
+            dst.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+
+            let (name, len) = if rest.starts_with('$') {
+                dst.push('$');
+                rest = &rest[1..];
+                continue;
+            } else if rest.starts_with('{') {
+                match rest.find('}') {
+                    Some(end) => (&rest[1..end], end + 1),
+                    None => ("", 0),
+                }
+            } else {
+                let end = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                (&rest[..end], end)
+            };
+            if name.is_empty() {
+                dst.push('$');
+                continue;
+            }
+
+            let text = match name.parse::<usize>() {
+                Ok(group) => self.get(group),
+                Err(_) => self.name(name),
+            };
+            dst.push_str(text.unwrap_or(""));
+            rest = &rest[len..];
+        }
+        dst.push_str(rest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn engines() {
+        assert!(Regex::new("ab+c").unwrap().dfa.is_some());
+        assert!(Regex::new("\\w+@\\w+").unwrap().lazy.is_some());
+        assert!(Regex::new("\\bfoo\\b").unwrap().lazy.is_some());
+
+        let regex = Regex::new("a(?=b)").unwrap();
+        assert!(regex.dfa.is_none() && regex.lazy.is_none() && !regex.program.has_backrefs());
+        assert!(Regex::new("(a)\\1").unwrap().program.has_backrefs());
+
+        let regex = Regex::new("if|else|elsif").unwrap();
+        assert!(regex.ahocorasick.is_some() && regex.dfa.is_none() && regex.lazy.is_none());
+        assert_eq!(
+            regex.find_iter("if else elsif").collect::<Vec<_>>(),
+            vec![(0, 2), (3, 7), (8, 13)]
+        );
+        assert_eq!(regex.captures("elsif").unwrap().pos(0), Some((0, 5)));
+    }
+
+    #[test]
+    fn is_match_and_find() {
+        for pattern in &[
+            "ab+c",
+            "(x+x+)+y(abc|abd|abe|abf|abg|abh|abi|abj)",
+            "a(?=b)|c",
+            "(a|c)\\1?",
+            "(a)(?:b*)*\\1",
+            "(a)(?:)*x\\1",
+            "abc|bc|b|ab|",
+            "é|aé|x",
+        ] {
+            let regex = Regex::new(pattern).unwrap();
+            let program = AST::parse(pattern).unwrap().into_program();
+
+            for s in &["xabbbc", "xxyabj", "ab", "zzc", "", "aac", "xyz", "aé"] {
+                let expected = program.find_backtracking(s);
+                assert_eq!(regex.is_match(s), expected.is_some(), "{} {:?}", pattern, s);
+                assert_eq!(regex.find(s), expected, "{} {:?}", pattern, s);
+            }
+        }
+    }
+
+    #[test]
+    fn prefilter() {
+        for pattern in &[
+            "foo\\d+",
+            "\\bab[0-9]",
+            "(?<=x)abc",
+            "(ab)\\1c",
+            "(?m)^.*ERROR: ([0-9]+)",
+        ] {
+            let regex = Regex::new(pattern).unwrap();
+            assert!(regex.prefilter.is_some(), "{}", pattern);
+            let mut plain = Regex::new(pattern).unwrap();
+            plain.prefilter = None;
+
+            for s in &[
+                "foo12 xfoo3 foo",
+                "cab1 ab2 ab",
+                "abc xabc xxabc",
+                "ababc abab ababc",
+                "x: ERROR: 1\nok\nERROR: 23 ERROR: 4",
+                "nothing",
+            ] {
+                let expected: Vec<_> = plain.find_iter(s).collect();
+                assert_eq!(
+                    regex.find_iter(s).collect::<Vec<_>>(),
+                    expected,
+                    "{} {:?}",
+                    pattern,
+                    s
+                );
+                assert_eq!(
+                    regex.is_match(s),
+                    !expected.is_empty(),
+                    "{} {:?}",
+                    pattern,
+                    s
+                );
+                assert_eq!(
+                    regex.captures(s).map(|c| c.pos(1)),
+                    plain.captures(s).map(|c| c.pos(1))
+                );
+            }
+        }
+
+        assert!(Regex::new("a*|b").unwrap().prefilter.is_none());
+    }
+
+    #[test]
+    fn word_boundaries() {
+        // `\b` sees the same word characters as `\w`, combining marks included
+        let regex = Regex::new("\\b").unwrap();
+        let s = "e\u{301}x y";
+        assert_eq!(
+            regex.find_iter(s).collect::<Vec<_>>(),
+            vec![(0, 0), (4, 4), (5, 5), (6, 6)]
+        );
+
+        // and in ASCII-only mode, the same as ASCII-only `\w`
+        for regex in &[
+            Regex::new("(?a)\\w\\b").unwrap(),
+            RegexBuilder::new("\\w\\b").unicode(false).build().unwrap(),
+        ] {
+            assert_eq!(regex.find("aé"), Some((0, 1)));
+            assert_eq!(regex.find("é"), None);
+        }
+    }
+
+    #[test]
+    fn captures() {
+        let regex = Regex::new("(?<year>\\d\\d\\d\\d)-(\\d\\d)(-(\\d\\d))?").unwrap();
+        let captures = regex.captures("on 2024-05").unwrap();
+
+        assert_eq!(captures.count(), 5);
+        assert_eq!(captures.get(0), Some("2024-05"));
+        assert_eq!(captures.pos(0), Some((3, 10)));
+        assert_eq!(captures.name("year"), Some("2024"));
+        assert_eq!(captures.get(2), Some("05"));
+        assert_eq!(captures.get(3), None);
+        assert_eq!(captures.name("month"), None);
+        assert!(regex.captures("2024").is_none());
+
+        let regex = Regex::new("(\\w)\\1").unwrap();
+        assert_eq!(regex.captures("abccd").unwrap().get(1), Some("c"));
+
+        let regex = Regex::new("(?=(a))a").unwrap();
+        assert_eq!(regex.captures("a").unwrap().count(), 2);
+    }
+
+    #[test]
+    fn captures_iter() {
+        let regex = Regex::new("a*").unwrap();
+        let matches: Vec<_> = regex
+            .captures_iter("baaaçc")
+            .map(|caps| caps.pos(0).unwrap())
+            .collect();
+        assert_eq!(matches, vec![(0, 0), (1, 4), (6, 6), (7, 7)]);
+        assert_eq!(regex.find_iter("baaaçc").collect::<Vec<_>>(), matches);
+
+        let regex = Regex::new("\\b(\\w)(\\w)*\\1\\b").unwrap();
+        let words: Vec<_> = regex
+            .captures_iter("dad did not see a deed")
+            .map(|caps| caps.get(0).unwrap())
+            .collect();
+        assert_eq!(words, vec!["dad", "did", "deed"]);
+        assert_eq!(Regex::new("x").unwrap().captures_iter("abc").count(), 0);
+    }
+
+    #[test]
+    fn iterating_is_linear() {
+        // every step used to go over the whole text again, for lookarounds and backreferences
+        let text = "ab ".repeat(20_000);
+        for pattern in &["(?<=a)b", "b(?= )", "(a)\\1|b"] {
+            let regex = Regex::new(pattern).unwrap();
+            let start = Instant::now();
+            assert_eq!(regex.find_iter(&text).count(), 20_000);
+            assert_eq!(regex.captures_iter(&text).count(), 20_000);
+            assert!(start.elapsed() < Duration::from_secs(10), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn split() {
+        let regex = Regex::new("\\s*,\\s*").unwrap();
+        let pieces: Vec<_> = regex.split("a, b ,,c ,").collect();
+        assert_eq!(pieces, vec!["a", "b", "", "c", ""]);
+        assert_eq!(regex.split("").collect::<Vec<_>>(), vec![""]);
+        assert_eq!(regex.split("abc").collect::<Vec<_>>(), vec!["abc"]);
+
+        let pieces: Vec<_> = Regex::new("").unwrap().split("aé").collect();
+        assert_eq!(pieces, vec!["", "a", "é", ""]);
+        let pieces: Vec<_> = Regex::new("x*").unwrap().split("axxb").collect();
+        assert_eq!(pieces, vec!["", "a", "b", ""]);
+
+        let pieces: Vec<_> = regex.splitn("a, b ,,c ,", 3).collect();
+        assert_eq!(pieces, vec!["a", "b", ",c ,"]);
+        assert_eq!(regex.splitn("a,b", 5).collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(regex.splitn("a,b", 1).collect::<Vec<_>>(), vec!["a,b"]);
+        assert_eq!(regex.splitn("a,b", 0).count(), 0);
+    }
+
+    #[test]
+    fn replace() {
+        let regex = Regex::new("(?<key>\\w+)=(\\w+)").unwrap();
+        assert_eq!(regex.replace("a=1 b=2", "$2=$key"), "1=a b=2");
+        assert_eq!(regex.replace_all("a=1 b=2", "${2}_${key}"), "1_a 2_b");
+        assert_eq!(regex.replacen("a=1 b=2 c=3", 2, "[$0]"), "[a=1] [b=2] c=3");
+        assert_eq!(regex.replace_all("a=1", "$$1 $3 $nope $ ${2"), "$1   $ ${2");
+        assert_eq!(regex.replace_all("a=1", "$2a ${2}a"), " 1a");
+        assert_eq!(regex.replace_all("a=1", NoExpand("$2")), "$2");
+        assert_eq!(
+            regex.replace_all("a=1 b=2", |caps: &Captures| caps.get(2).unwrap().repeat(2)),
+            "11 22"
+        );
+        assert_eq!(regex.replace_all("a=1", &"<$1>".to_string()), "<a>");
+
+        let regex = Regex::new("").unwrap();
+        assert_eq!(regex.replace_all("ab", "-"), "-a-b-");
+
+        match Regex::new("x").unwrap().replace_all("abc", "y") {
+            Cow::Borrowed(s) => assert_eq!(s, "abc"),
+            Cow::Owned(_) => panic!("nothing was replaced"),
+        }
+    }
+
+    #[test]
+    fn builder() {
+        let regex = RegexBuilder::new("^a.b$")
+            .case_insensitive(true)
+            .multi_line(true)
+            .dot_matches_new_line(true)
+            .build()
+            .unwrap();
+        assert!(regex.is_match("x\nA\nB\ny"));
+
+        let regex = RegexBuilder::new("(a)\\1")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        assert_eq!(regex.find("aA"), Some((0, 2)));
+
+        let regex = RegexBuilder::new("\\w").unicode(false).build().unwrap();
+        assert!(!regex.is_match("é"));
+        assert!(Regex::new("\\w").unwrap().is_match("é"));
+    }
+
+    #[test]
+    fn size_limits() {
+        match RegexBuilder::new("a+b+c+").nfa_size_limit(5).build() {
+            Err(Error(ErrorKind::SizeLimit(..), _)) => {}
+            _ => panic!("the NFA is too big"),
+        }
+        match RegexBuilder::new("(?=a+b+c+)").nfa_size_limit(5).build() {
+            Err(Error(ErrorKind::SizeLimit(..), _)) => {}
+            _ => panic!("the NFA of the lookahead is too big"),
+        }
+
+        let pattern = "[ab]*a[ab][ab][ab][ab]";
+        assert!(Regex::new(pattern).unwrap().dfa.is_some());
+
+        let regex = RegexBuilder::new(pattern)
+            .dfa_size_limit(16)
+            .cache_size(4)
+            .build()
+            .unwrap();
+        assert!(regex.dfa.is_none());
+        assert!(regex.is_match("bbabbbb"));
+        assert!(!regex.is_match("bbbbabbb"));
+
+        // states stay cached across queries, up to the cache size
+        let (ref lazy, ref cache) = *regex.lazy.as_ref().unwrap();
+        let states = lazy.states(&cache.lock().unwrap());
+        assert!(states > 0 && states <= 4);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Regex::new("(").is_err());
+        assert_eq!(Regex::new("a|b").unwrap().to_string(), "a|b");
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::BTreeSet;
+use {Flags, State, AST, DFA, NFA};
+
+use errors::*;
+
+/// Many patterns matched in a single pass over the input. The union of the NFAs of the patterns,
+/// preceded by `(?s:.*)`, is turned into a DFA whose accepting states know which patterns they
+/// match. The patterns with a match are those of the accepting states the DFA goes through while
+/// reading the input.
+///
+/// Only the start is left open: with `(?s:.*)` after every pattern too, DFA states would have
+/// to remember every subset of the patterns matched so far, and their number would grow
+/// exponentially with the number of patterns.
+///
+/// Sets with lookarounds, or whose DFA is beyond its size limit, are simulated on the NFA
+/// instead, which is still one pass but much slower per character. Backreferences are not
+/// supported.
+#[derive(Debug)]
+pub struct RegexSet {
+    patterns: Vec<String>,
+    nfa: NFA,
+    accepts: Vec<BTreeSet<State>>,
+    dfa: Option<DFA>,
+}
+
+/// Options for compiling a `RegexSet`, which apply to all its patterns.
+#[derive(Debug, Clone)]
+pub struct RegexSetBuilder {
+    patterns: Vec<String>,
+    flags: Flags,
+    dfa_size_limit: usize,
+}
+
+impl RegexSetBuilder {
+    pub fn new<I, S>(patterns: I) -> RegexSetBuilder
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        RegexSetBuilder {
+            patterns: patterns
+                .into_iter()
+                .map(|p| p.as_ref().to_string())
+                .collect(),
+            flags: Flags::default(),
+            dfa_size_limit: 10_000,
+        }
+    }
+
+    /// Same as `(?i)`.
+    pub fn case_insensitive(mut self, yes: bool) -> RegexSetBuilder {
+        self.flags.case_insensitive = yes;
+        self
+    }
+
+    /// Same as `(?m)`.
+    pub fn multi_line(mut self, yes: bool) -> RegexSetBuilder {
+        self.flags.multi_line = yes;
+        self
+    }
+
+    /// Same as `(?s)`.
+    pub fn dot_matches_new_line(mut self, yes: bool) -> RegexSetBuilder {
+        self.flags.dot_matches_new_line = yes;
+        self
+    }
+
+    /// Turning Unicode off is the same as `(?a)`.
+    pub fn unicode(mut self, yes: bool) -> RegexSetBuilder {
+        self.flags.ascii_only = !yes;
+        self
+    }
+
+    /// Largest number of states of the DFA. Beyond that, the NFA is simulated.
+    pub fn dfa_size_limit(mut self, states: usize) -> RegexSetBuilder {
+        self.dfa_size_limit = states;
+        self
+    }
+
+    pub fn build(&self) -> Result<RegexSet> {
+        let mut nfas = Vec::new();
+        for pattern in &self.patterns {
+            nfas.push(AST::parse_with(pattern, self.flags)?.into_nfa()?);
+        }
+
+        let (nfa, accepts) = NFA::union(&nfas);
+        let nfa = nfa.unanchored();
+        let dfa = if nfa.looks.is_empty() {
+            match nfa.to_set_dfa(&accepts, self.dfa_size_limit) {
+                Ok(dfa) => Some(dfa),
+                Err(Error(ErrorKind::SizeLimit(..), _)) => None,
+                Err(e) => return Err(e),
+            }
+        } else {
+            None
+        };
+
+        Ok(RegexSet {
+            patterns: self.patterns.clone(),
+            nfa,
+            accepts,
+            dfa,
+        })
+    }
+}
+
+impl RegexSet {
+    /// Compiles `patterns` with the default options of `RegexSetBuilder`.
+    pub fn new<I, S>(patterns: I) -> Result<RegexSet>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        RegexSetBuilder::new(patterns).build()
+    }
+
+    /// Indices of the patterns matching some substring of `s`, in increasing order.
+    pub fn matches(&self, s: &str) -> Vec<usize> {
+        match self.dfa {
+            Some(ref dfa) => dfa.matches(s),
+            None => self.nfa.matches(s, &self.accepts),
+        }
+    }
+
+    /// Checks if any of the patterns matches some substring of `s`.
+    pub fn is_match(&self, s: &str) -> bool {
+        !self.matches(s).is_empty()
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use Regex;
+
+    #[test]
+    fn matches() {
+        let patterns = [
+            "error",
+            "^GET ",
+            "[0-9][0-9][0-9]$",
+            "timeout|refused",
+            "(?i)warn",
+        ];
+        let set = RegexSet::new(patterns).unwrap();
+        assert!(set.dfa.is_some());
+        assert_eq!(set.len(), 5);
+
+        for s in &[
+            "GET /index.html 200",
+            "POST /login 500: error",
+            "connection refused, WARNING",
+            "nothing to see",
+            "",
+        ] {
+            let expected: Vec<usize> = (0..patterns.len())
+                .filter(|&i| Regex::new(patterns[i]).unwrap().is_match(s))
+                .collect();
+            assert_eq!(set.matches(s), expected, "{:?}", s);
+            assert_eq!(set.is_match(s), !expected.is_empty());
+        }
+    }
+
+    #[test]
+    fn without_dfa() {
+        let set = RegexSet::new(["a(?=b)", "c"]).unwrap();
+        assert!(set.dfa.is_none());
+        assert_eq!(set.matches("xabc"), vec![0, 1]);
+        assert_eq!(set.matches("xac"), vec![1]);
+
+        let set = RegexSetBuilder::new(["[ab]*a[ab][ab][ab][ab]", "b"])
+            .dfa_size_limit(16)
+            .build()
+            .unwrap();
+        assert!(set.dfa.is_none());
+        assert_eq!(set.matches("bbabbbb"), vec![0, 1]);
+        assert_eq!(set.matches("bbbbabbb"), vec![1]);
+    }
+
+    #[test]
+    fn many_patterns() {
+        let patterns: Vec<String> = (0..300).map(|i| format!("word{}x", i)).collect();
+        let start = Instant::now();
+        let set = RegexSet::new(&patterns).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        // one state per prefix of a word, give or take
+        assert!(set.dfa.as_ref().unwrap().states().len() < 2_000);
+
+        let line = format!("{} word7x word299 word250x", "words ".repeat(90));
+        assert_eq!(set.matches(&line), vec![7, 250]);
+    }
+
+    #[test]
+    fn options_and_errors() {
+        let set = RegexSetBuilder::new(["^b$", "A"])
+            .case_insensitive(true)
+            .multi_line(true)
+            .build()
+            .unwrap();
+        assert_eq!(set.matches("a\nB"), vec![0, 1]);
+
+        assert!(RegexSet::new(Vec::<String>::new())
+            .unwrap()
+            .matches("a")
+            .is_empty());
+        assert!(RegexSet::new(["a", "("]).is_err());
+        match RegexSet::new(["(a)\\1"]) {
+            Err(Error(ErrorKind::UnsupportedFeature(..), _)) => {}
+            _ => panic!("backreferences are not supported"),
+        }
+    }
+}
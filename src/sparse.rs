@@ -0,0 +1,67 @@
+/// Sparse set of integers in `0..capacity` (Briggs and Torczon, 1993).
+///
+/// Insertion, membership test and clearing are all `O(1)`, and iteration goes over the elements
+/// in insertion order, which is what keeps thread priorities in the simulations.
+#[derive(Debug, Clone)]
+pub struct SparseSet {
+    dense: Vec<u32>,
+    sparse: Vec<u32>,
+}
+
+impl SparseSet {
+    pub fn new(capacity: usize) -> SparseSet {
+        SparseSet {
+            dense: Vec::with_capacity(capacity),
+            sparse: vec![0; capacity],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dense.is_empty()
+    }
+
+    pub fn contains(&self, value: u32) -> bool {
+        let i = self.sparse[value as usize] as usize;
+        i < self.dense.len() && self.dense[i] == value
+    }
+
+    /// Returns `false` if the value was already present.
+    pub fn insert(&mut self, value: u32) -> bool {
+        if self.contains(value) {
+            return false;
+        }
+
+        self.sparse[value as usize] = self.dense.len() as u32;
+        self.dense.push(value);
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.dense.clear();
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<'_, u32> {
+        self.dense.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparse_set() {
+        let mut set = SparseSet::new(10);
+
+        assert!(set.insert(7));
+        assert!(set.insert(2));
+        assert!(!set.insert(7));
+        assert!(set.contains(2));
+        assert!(!set.contains(3));
+        assert_eq!(set.iter().cloned().collect::<Vec<_>>(), vec![7, 2]);
+
+        set.clear();
+        assert!(set.is_empty());
+        assert!(!set.contains(7));
+    }
+}
@@ -0,0 +1,241 @@
+use std::io::{ErrorKind as IoErrorKind, Read};
+use std::str;
+
+use errors::*;
+
+/// Automata that can be fed their input one character at a time, see `Stream`.
+pub trait Matcher {
+    fn step(&mut self, c: char);
+
+    /// Checks if the input so far is accepted, if it were to end here.
+    fn is_accepting(&self) -> bool;
+
+    /// Checks if the input so far is accepted, with `c` coming next.
+    fn is_accepting_before(&self, c: char) -> bool;
+}
+
+/// Runs a matcher over input given in chunks of bytes, from a file or a socket, without ever
+/// holding more than a chunk of it. Characters can be split between chunks.
+///
+/// Every time the input so far is accepted, the byte offset of its end in the whole stream is
+/// reported, so for an automaton of `(?s:.*)pattern` these are the ends of the matches of the
+/// pattern. An end is only reported once the character after it is known, so that assertions
+/// about it, like `$` or `\b`, can see it: the end of a chunk is reported with the next one,
+/// and the end of the stream by `finish`.
+#[derive(Debug)]
+pub struct Stream<M> {
+    matcher: M,
+    /// Start of a character the last chunk ended in the middle of.
+    pending: Vec<u8>,
+    /// Bytes of the characters given to the matcher so far.
+    offset: usize,
+}
+
+/// Size of the chunks `Stream::read` reads.
+const CHUNK: usize = 64 * 1024;
+
+impl<M: Matcher> Stream<M> {
+    pub fn new(matcher: M) -> Stream<M> {
+        Stream {
+            matcher,
+            pending: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    /// Bytes of the stream read so far, not counting a character that is not complete yet.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Checks if the stream so far is accepted, if it were to end here. This is the only way to
+    /// know about the empty prefix, which no chunk reports.
+    pub fn is_accepting(&self) -> bool {
+        self.matcher.is_accepting()
+    }
+
+    /// Reports the end of the input so far, now that `c` is known to come next, then reads `c`.
+    fn push(&mut self, c: char, f: &mut dyn FnMut(usize)) {
+        if self.offset > 0 && self.matcher.is_accepting_before(c) {
+            f(self.offset);
+        }
+        self.matcher.step(c);
+        self.offset += c.len_utf8();
+    }
+
+    /// Feeds the next chunk of the stream, calling `f` with the end of every accepted prefix
+    /// followed by a character of the chunk. Fails with `ErrorKind::InvalidUtf8` if the stream
+    /// is not UTF-8.
+    pub fn feed_with<F: FnMut(usize)>(&mut self, chunk: &[u8], mut f: F) -> Result<()> {
+        let mut bytes = chunk;
+        while !self.pending.is_empty() && !bytes.is_empty() {
+            self.pending.push(bytes[0]);
+            bytes = &bytes[1..];
+            let c = match str::from_utf8(&self.pending) {
+                Ok(s) => s.chars().next().unwrap(),
+                Err(e) if e.error_len().is_some() => {
+                    bail!(ErrorKind::InvalidUtf8(self.offset));
+                }
+                Err(_) => continue,
+            };
+            self.pending.clear();
+            self.push(c, &mut f);
+        }
+        if !self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let (valid, error) = match str::from_utf8(bytes) {
+            Ok(s) => (s, None),
+            Err(e) => (str::from_utf8(&bytes[..e.valid_up_to()]).unwrap(), Some(e)),
+        };
+        for c in valid.chars() {
+            self.push(c, &mut f);
+        }
+        match error {
+            Some(e) if e.error_len().is_some() => bail!(ErrorKind::InvalidUtf8(self.offset)),
+            Some(e) => self.pending.extend(&bytes[e.valid_up_to()..]),
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    /// Same as `feed_with`, returning the ends of the accepted prefixes.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Vec<usize>> {
+        let mut ends = Vec::new();
+        self.feed_with(chunk, |end| ends.push(end))?;
+        Ok(ends)
+    }
+
+    /// Feeds everything `reader` has, chunk by chunk, see `feed_with`.
+    pub fn read<R: Read, F: FnMut(usize)>(&mut self, mut reader: R, mut f: F) -> Result<()> {
+        let mut buffer = vec![0; CHUNK];
+        loop {
+            let n = match reader.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == IoErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).chain_err(|| "unable to read input"),
+            };
+            self.feed_with(&buffer[..n], &mut f)?;
+        }
+    }
+
+    /// Ends the stream, and checks if all of it is accepted, like `Automaton::run` does for a
+    /// string. This is the only report of the end of the stream.
+    pub fn finish(self) -> Result<bool> {
+        if !self.pending.is_empty() {
+            bail!(ErrorKind::InvalidUtf8(self.offset));
+        }
+
+        Ok(self.matcher.is_accepting())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use {Automaton, AST};
+
+    #[test]
+    fn chunks() {
+        let nfa = AST::parse("(?s:.*)é+\\b").unwrap().into_nfa().unwrap();
+        let dfa = nfa.to_dfa().unwrap();
+        let text = "aéé éb é";
+        // the end of the stream is left to `finish`
+        let expected = vec![5];
+
+        for split in 0..text.len() {
+            let (first, second) = text.as_bytes().split_at(split);
+
+            let mut stream = dfa.stream();
+            let mut ends = stream.feed(first).unwrap();
+            ends.extend(stream.feed(second).unwrap());
+            assert_eq!(ends, expected, "{}", split);
+            assert_eq!(stream.finish().unwrap(), dfa.run(text));
+
+            let mut stream = nfa.stream().unwrap();
+            let mut ends = stream.feed(first).unwrap();
+            ends.extend(stream.feed(second).unwrap());
+            assert_eq!(ends, expected, "{}", split);
+            assert_eq!(stream.finish().unwrap(), nfa.run(text));
+        }
+    }
+
+    #[test]
+    fn read() {
+        let dfa = AST::parse("(?s:.*)ab")
+            .unwrap()
+            .into_nfa()
+            .unwrap()
+            .to_dfa()
+            .unwrap();
+        let text = "xab".repeat(CHUNK);
+
+        let mut stream = dfa.stream();
+        let mut count = 0;
+        let mut last = 0;
+        stream
+            .read(Cursor::new(text.as_bytes()), |end| {
+                count += 1;
+                last = end;
+            })
+            .unwrap();
+        assert_eq!((count, last), (CHUNK - 1, 3 * CHUNK - 3));
+        assert_eq!(stream.offset(), 3 * CHUNK);
+        assert!(stream.is_accepting());
+        assert!(stream.finish().unwrap());
+    }
+
+    #[test]
+    fn look_ahead() {
+        for (pattern, text, expected) in [
+            ("(?s:.*)ab\\b", "abc ab", vec![]),
+            ("(?s:.*)ab\\b", "ab abc", vec![2]),
+            ("(?s:.*)a$", "aaa", vec![]),
+            ("(?m)(?s:.*)a$", "a\naa\n", vec![1, 4]),
+        ] {
+            let nfa = AST::parse(pattern).unwrap().into_nfa().unwrap();
+            let dfa = nfa.to_dfa().unwrap();
+            for split in 0..text.len() {
+                let (first, second) = text.as_bytes().split_at(split);
+
+                let mut stream = dfa.stream();
+                let mut ends = stream.feed(first).unwrap();
+                ends.extend(stream.feed(second).unwrap());
+                assert_eq!(ends, expected, "{} {}", pattern, split);
+                assert_eq!(stream.finish().unwrap(), dfa.run(text), "{}", pattern);
+
+                let mut stream = nfa.stream().unwrap();
+                let mut ends = stream.feed(first).unwrap();
+                ends.extend(stream.feed(second).unwrap());
+                assert_eq!(ends, expected, "{} {}", pattern, split);
+                assert_eq!(stream.finish().unwrap(), nfa.run(text), "{}", pattern);
+            }
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let nfa = AST::parse("a").unwrap().into_nfa().unwrap();
+        let dfa = nfa.to_dfa().unwrap();
+        let mut stream = dfa.stream();
+        assert!(!stream.is_accepting());
+        match stream.feed(b"a\xFFa") {
+            Err(Error(ErrorKind::InvalidUtf8(1), _)) => {}
+            _ => panic!("the stream is not UTF-8"),
+        }
+
+        let mut stream = nfa.stream().unwrap();
+        assert_eq!(stream.feed("a".as_bytes()).unwrap(), vec![]);
+        assert_eq!(stream.feed(&"é".as_bytes()[..1]).unwrap(), vec![]);
+        assert!(stream.finish().is_err());
+
+        match AST::parse("a(?=b)").unwrap().into_nfa().unwrap().stream() {
+            Err(Error(ErrorKind::UnsupportedFeature(..), _)) => {}
+            _ => panic!("lookarounds can't be streamed"),
+        }
+    }
+}
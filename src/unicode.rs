@@ -0,0 +1,121 @@
+use std::sync::OnceLock;
+use unicode_tables::{Table, BINARY, GENERAL_CATEGORY, SCRIPT};
+use CharClass;
+
+/// Property names are compared the way UTS #18 recommends: ignoring case, spaces, `_` and `-`.
+fn loose(name: &str) -> String {
+    name.chars()
+        .filter(|&c| c != ' ' && c != '_' && c != '-')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn lookup(table: Table, name: &str) -> Option<CharClass> {
+    let name = loose(name);
+    table
+        .iter()
+        .find(|&&(names, _)| names.iter().any(|&n| loose(n) == name))
+        .map(|&(_, ranges)| CharClass::new(ranges.to_vec()))
+}
+
+/// Property classes, from tables generated by `scripts/unicode-tables.pl`.
+impl CharClass {
+    /// Characters with a Unicode property, named like in `\p{...}`: a general category (`L`,
+    /// `Lu`, `Uppercase_Letter`), a script (`Greek`, `Grek`), a binary property (`Alphabetic`,
+    /// `White_Space`, ...), or one of `Any`, `ASCII` and `Assigned`. Values can also be given
+    /// as `gc=...`, `General_Category=...`, `sc=...` or `Script=...`.
+    pub fn property(name: &str) -> Option<CharClass> {
+        if let Some((key, value)) = name.split_once('=') {
+            return match loose(key).as_str() {
+                "gc" | "generalcategory" => lookup(GENERAL_CATEGORY, value),
+                "sc" | "script" => lookup(SCRIPT, value),
+                _ => None,
+            };
+        }
+
+        match loose(name).as_str() {
+            "any" => Some(CharClass::any()),
+            "ascii" => Some(CharClass::new(vec![('\0', '\x7F')])),
+            "assigned" => lookup(GENERAL_CATEGORY, "Cn").map(|class| class.negate()),
+            _ => lookup(GENERAL_CATEGORY, name)
+                .or_else(|| lookup(SCRIPT, name))
+                .or_else(|| lookup(BINARY, name)),
+        }
+    }
+
+    /// Word characters as `\b` sees them, the same as `\w` in Unicode mode. It is built once,
+    /// as `\b` needs it for every character it looks at.
+    pub fn word() -> &'static CharClass {
+        static WORD: OnceLock<CharClass> = OnceLock::new();
+        WORD.get_or_init(|| {
+            ["Alphabetic", "M", "Nd", "Pc", "Join_Control"]
+                .iter()
+                .fold(CharClass::new(vec![]), |class, name| {
+                    class.union(&CharClass::property(name).unwrap())
+                })
+        })
+    }
+
+    /// `\d`, `\w` or `\s`. In Unicode mode they follow UTS #18: decimal numbers, word
+    /// characters (letters, marks, decimal numbers, connector punctuation and joiners) and
+    /// white space. In ASCII-only mode they are `[0-9]`, `[0-9A-Za-z_]` and `[\t\n\v\f\r ]`.
+    pub fn perl(name: char, ascii_only: bool) -> Option<CharClass> {
+        let property = |name| CharClass::property(name).unwrap();
+        Some(match (name, ascii_only) {
+            ('d', false) => property("Nd"),
+            ('d', true) => CharClass::new(vec![('0', '9')]),
+            ('w', false) => CharClass::word().clone(),
+            ('w', true) => CharClass::new(vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')]),
+            ('s', false) => property("White_Space"),
+            ('s', true) => CharClass::new(vec![('\t', '\r'), (' ', ' ')]),
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn property() {
+        let greek = CharClass::property("Greek").unwrap();
+
+        assert!(greek.contains('λ'));
+        assert!(!greek.contains('a'));
+        assert_eq!(CharClass::property("sc=Grek"), Some(greek.clone()));
+        assert_eq!(CharClass::property("Script = greek"), Some(greek));
+
+        let letters = CharClass::property("L").unwrap();
+        assert!(letters.contains('a') && letters.contains('ж') && letters.contains('中'));
+        assert!(!letters.contains('1'));
+        assert_eq!(
+            CharClass::property("Uppercase_Letter"),
+            CharClass::property("gc=Lu")
+        );
+        assert!(CharClass::property("Lu").unwrap().contains('Ä'));
+        assert!(CharClass::property("whitespace")
+            .unwrap()
+            .contains('\u{3000}'));
+        assert!(!CharClass::property("Assigned").unwrap().contains('\u{378}'));
+        assert_eq!(CharClass::property("Klingon"), None);
+        assert_eq!(CharClass::property("foo=L"), None);
+    }
+
+    #[test]
+    fn perl() {
+        let digits = CharClass::perl('d', false).unwrap();
+        assert!(digits.contains('٣'));
+        assert!(!CharClass::perl('d', true).unwrap().contains('٣'));
+
+        let word = CharClass::perl('w', false).unwrap();
+        assert!(word.contains('é') && word.contains('_') && word.contains('\u{301}'));
+        assert!(!word.contains('-'));
+        assert!(!CharClass::perl('w', true).unwrap().contains('é'));
+
+        assert!(CharClass::perl('s', false).unwrap().contains('\u{A0}'));
+        assert!(CharClass::perl('s', true).unwrap().contains('\x0B'));
+        assert!(!CharClass::perl('s', true).unwrap().contains('\u{A0}'));
+        assert_eq!(CharClass::perl('q', false), None);
+    }
+}